@@ -1,16 +1,45 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::{path::PathBuf, str::FromStr, time::Duration};
 use clap::{Parser, Subcommand};
 
-use client::Client;
+use client::{Address, Client, CodecKind, TlsClient};
+use common::dto::AuditQuery;
 
 #[derive(Parser)]
 #[command(about="Dictionary client, used to call dictionary server endpoints", long_about=None)]
 #[command(author, version, propagate_version=true)]
 struct Cli {
     #[arg(short, long, value_name="ADDRESS")]
-    #[arg(help="Server address; alternatively, set the DICT_ADDR env var")]
+    #[arg(help="Server address, or unix:/path for a Unix socket; alternatively, set the DICT_ADDR env var")]
     address: Option<String>,
 
+    #[arg(short, long, value_name="SECS", default_value_t=30)]
+    #[arg(help="Time to wait for the response; 0 waits forever")]
+    timeout: u64,
+
+    #[arg(long, value_name="FILE")]
+    #[arg(help="PEM file of the CA certificates to trust; enables TLS")]
+    tls_ca: Option<PathBuf>,
+
+    #[arg(long, value_name="NAME", default_value="localhost")]
+    #[arg(help="Server name expected in its TLS certificate")]
+    tls_server_name: String,
+
+    #[arg(long, value_name="FILE", requires_all=["tls_ca", "tls_key"])]
+    #[arg(help="PEM client certificate chain, for servers requiring one")]
+    tls_cert: Option<PathBuf>,
+
+    #[arg(long, value_name="FILE", requires="tls_cert")]
+    #[arg(help="PEM private key of the client certificate")]
+    tls_key: Option<PathBuf>,
+
+    #[arg(long, value_name="CODEC", default_value_t=CodecKind::Bincode, value_parser=CodecKind::from_str)]
+    #[arg(help="Codec of the connection: json, bincode, msgpack or cbor")]
+    codec: CodecKind,
+
+    #[arg(short, long, value_name="USER")]
+    #[arg(help="User to authenticate as; the token is read from the DICT_TOKEN env var")]
+    user: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -35,8 +64,33 @@ enum Commands {
         val: String,
     },
 
+    #[command(about = "Delete key")]
+    Delete {
+        #[arg(short, long, value_name="KEY")]
+        #[arg(help="Key")]
+        key: String
+    },
+
     #[command(about = "Get stats")]
     Stats,
+
+    #[command(about = "Reset stats")]
+    ResetStats,
+
+    #[command(about = "Query the audit log of mutating operations")]
+    Audit {
+        #[arg(short, long, value_name="KEY")]
+        #[arg(help="Only entries of this key")]
+        key: Option<String>,
+
+        #[arg(long, value_name="SECS")]
+        #[arg(help="Only entries at or after this time, in UNIX seconds")]
+        since: Option<u64>,
+
+        #[arg(long, value_name="SECS")]
+        #[arg(help="Only entries before this time, in UNIX seconds")]
+        until: Option<u64>,
+    },
 }
 
 #[tokio::main]
@@ -48,21 +102,68 @@ pub async fn main() -> anyhow::Result<()> {
         None => std::env::var("DICT_ADDR").expect("Provide an address or set the DICT_ADDR env var"),
     };
     
-    let address = SocketAddr::from_str(&address)
+    let address = Address::from_str(&address)
         .expect("Unable to parse address");
 
-    let mut client = Client::new(address);
+    let builder = match cli.timeout {
+        0 => Client::builder(address).no_timeout(),
+        secs => Client::builder(address).timeout(Duration::from_secs(secs)),
+    };
+
+    let mut builder = builder
+        .codec(cli.codec)
+        .pool_size(1)
+        .no_health_checks();
+
+    if let Some(ca) = &cli.tls_ca {
+        let mut tls = TlsClient::new(ca, &cli.tls_server_name)?;
+        if let (Some(cert), Some(key)) = (&cli.tls_cert, &cli.tls_key) {
+            tls = tls.with_client_cert(cert, key)?;
+        }
+        builder = builder.tls(tls);
+    }
+
+    if let Some(user) = &cli.user {
+        let token = std::env::var("DICT_TOKEN").expect("Set the DICT_TOKEN env var to authenticate");
+        builder = builder.credentials(user, &token);
+    }
+
+    let client = builder.build();
     client.connect().await?;
     
-    let req = match &cli.command {
-        Commands::Get { key } => common::dto::Request::Get { key: key.clone() },
-        Commands::Set { key, val } => common::dto::Request::Set { key: key.clone(), val: val.clone() },
-        Commands::Stats => common::dto::Request::Stats,
+    let result = match &cli.command {
+        Commands::Get { key } => client.get(key).await.map(|val| match val {
+            Some(val) => println!("{}", val),
+            None => println!("Key {} not found", key),
+        }),
+        Commands::Set { key, val } => client.set(key, val).await.map(|()| println!("Stored key {}", key)),
+        Commands::Delete { key } => client.delete(key).await.map(|existed| match existed {
+            true => println!("Deleted key {}", key),
+            false => println!("Key {} not found", key),
+        }),
+        Commands::Stats => client.stats().await.map(|stats| println!("{:#?}", stats)),
+        Commands::ResetStats => client.reset_stats().await.map(|reset_at| println!("Stats reset at {}", reset_at)),
+        Commands::Audit { key, since, until } => {
+            let query = AuditQuery {
+                key: key.clone(),
+                since_ms: since.map(|s| s * 1000),
+                until_ms: until.map(|s| s * 1000),
+            };
+            client.audit(query).await.map(|entries| for entry in entries {
+                println!("{} {} {} {} {} {} {}",
+                    entry.timestamp_ms,
+                    entry.client,
+                    entry.address,
+                    entry.op,
+                    entry.key.as_deref().unwrap_or("-"),
+                    entry.value_hash.as_deref().unwrap_or("-"),
+                    entry.result);
+            })
+        },
     };
 
-    match client.send_request(req).await {
-        Ok(res) => println!("Received response {:?}", res),
-        Err(e) => eprintln!("Error {:?}", e),
+    if let Err(e) = result {
+        eprintln!("Error {}", e);
     }
 
     Ok(())
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use tokio::runtime::Runtime;
+
+use common::dto::{AuditEntry, AuditQuery, Request, Response, StatsReport};
+use common::net::Address;
+
+use crate::error::{ClientError, ClientResult};
+
+// Synchronous client, for programs without an async runtime
+// Wraps the async Client with a small runtime of its own, whose worker keeps the pooled
+// connections and their health checks running between calls. Clones share the runtime and the pool.
+// Must not be created, used or dropped from within an async context.
+#[derive(Clone)]
+pub struct Client {
+    inner: crate::Client,
+    runtime: Arc<Runtime>,
+}
+
+impl Client {
+    pub fn new(address: impl Into<Address>) -> ClientResult<Self> {
+        Self::builder(address).build_blocking()
+    }
+
+    /// Configures a client; finish with `build_blocking`
+    pub fn builder(address: impl Into<Address>) -> crate::ClientBuilder {
+        crate::Client::builder(address)
+    }
+
+    pub(crate) fn with_runtime(inner: crate::Client, runtime: Runtime) -> Self {
+        Self {
+            inner,
+            runtime: Arc::new(runtime),
+        }
+    }
+
+    /// Opens the pooled connections; otherwise they are opened on first use
+    pub fn connect(&self) -> ClientResult<()> {
+        self.runtime.block_on(self.inner.connect())
+    }
+
+    /// Checks that the server is reachable
+    pub fn ping(&self) -> ClientResult<()> {
+        self.runtime.block_on(self.inner.ping())
+    }
+
+    /// Gets the value of a key, or None if the key doesn't exist
+    pub fn get(&self, key: &str) -> ClientResult<Option<String>> {
+        self.runtime.block_on(self.inner.get(key))
+    }
+
+    /// Sets the value of a key
+    pub fn set(&self, key: &str, val: &str) -> ClientResult<()> {
+        self.runtime.block_on(self.inner.set(key, val))
+    }
+
+    /// Deletes a key; returns whether it existed
+    pub fn delete(&self, key: &str) -> ClientResult<bool> {
+        self.runtime.block_on(self.inner.delete(key))
+    }
+
+    /// Gets the server statistics
+    pub fn stats(&self) -> ClientResult<StatsReport> {
+        self.runtime.block_on(self.inner.stats())
+    }
+
+    /// Resets the server statistics; returns when they were reset, in seconds since the Unix epoch
+    pub fn reset_stats(&self) -> ClientResult<u64> {
+        self.runtime.block_on(self.inner.reset_stats())
+    }
+
+    /// Queries the server's audit log of mutating operations; requires admin permission
+    pub fn audit(&self, query: AuditQuery) -> ClientResult<Vec<AuditEntry>> {
+        self.runtime.block_on(self.inner.audit(query))
+    }
+
+    /// Sends a raw request to the server and waits for its response, retrying per the retry policy
+    /// Failures reported by the server are returned as responses; prefer the typed methods.
+    pub fn send_request(&self, request: Request) -> ClientResult<Response> {
+        self.runtime.block_on(self.inner.send_request(request))
+    }
+}
+
+// Runtime of the blocking clients
+pub(crate) fn runtime() -> ClientResult<Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("dict-client")
+        .enable_all()
+        .build()
+        .map_err(|e| ClientError::Connection(e.into()))
+}
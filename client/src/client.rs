@@ -1,41 +1,234 @@
-use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use common::dto::{Request, Response};
-use common::net::{Connection, BincodeConnection, Requester};
+use common::codec::CodecKind;
+use common::dto::{AuditEntry, AuditQuery, ErrorKind, Request, Response, StatsReport, Token};
+use common::net::Address;
+use common::tls::TlsClient;
 
-pub type ClientResult<T> = anyhow::Result<T>;
+use crate::blocking;
+use crate::error::{ClientError, ClientResult};
+use crate::mux::Endpoint;
+use crate::pool::Pool;
+use crate::retry::{retry_after, RetryPolicy};
+
+/// Default time to wait for a response
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default number of pooled connections
+pub const DEFAULT_POOL_SIZE: usize = 4;
+
+/// Default time between health checks of the pooled connections
+pub const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_secs(30);
 
 // Client library used to connect to Remote Dictionary server
-// TODO: add inner mutability for the connection field, so the client object can be used immutably
+// Clones share the same connection pool, so a single client can serve many tasks.
+// Requests can be sent concurrently, they are pipelined over the pooled connections.
+#[derive(Clone)]
 pub struct Client {
-    address: SocketAddr,
-    connection: Option<BincodeConnection>,
+    timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    pool: Arc<Pool>,
+}
+
+/// Configures a Client
+pub struct ClientBuilder {
+    address: Address,
+    tls: Option<TlsClient>,
+    codec: CodecKind,
+    credentials: Option<(String, Token)>,
+    timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    pool_size: usize,
+    health_interval: Option<Duration>,
+}
+
+impl ClientBuilder {
+    /// Sets how long to wait for each response, including connecting if needed;
+    /// the server is asked to give up by then as well
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Waits for responses indefinitely
+    pub fn no_timeout(mut self) -> Self {
+        self.timeout = None;
+        self
+    }
+
+    /// Encrypts the connections with TLS; the server must have TLS enabled
+    /// TLS isn't supported over Unix sockets.
+    pub fn tls(mut self, tls: TlsClient) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Sets the codec of the connections, bincode by default; the server must have it enabled
+    pub fn codec(mut self, codec: CodecKind) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Authenticates every connection with a user's token, for servers requiring it
+    pub fn credentials(mut self, user: &str, token: &str) -> Self {
+        self.credentials = Some((user.to_owned(), Token(token.to_owned())));
+        self
+    }
+
+    /// Sets how failed requests are retried
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets the number of connections shared by the client and its clones
+    pub fn pool_size(mut self, pool_size: usize) -> Self {
+        self.pool_size = pool_size.max(1);
+        self
+    }
+
+    /// Sets how often idle connections are pinged; unhealthy ones are reopened on next use
+    pub fn health_interval(mut self, interval: Duration) -> Self {
+        self.health_interval = Some(interval);
+        self
+    }
+
+    /// Disables the health checks
+    pub fn no_health_checks(mut self) -> Self {
+        self.health_interval = None;
+        self
+    }
+
+    /// Builds a synchronous client instead, see `blocking::Client`
+    pub fn build_blocking(self) -> ClientResult<blocking::Client> {
+        let runtime = blocking::runtime()?;
+        Ok(blocking::Client::with_runtime(self.build(), runtime))
+    }
+
+    pub fn build(self) -> Client {
+        Client {
+            timeout: self.timeout,
+            retry_policy: self.retry_policy,
+            pool: Pool::new(Endpoint { address: self.address, tls: self.tls, codec: self.codec, credentials: self.credentials }, self.pool_size, self.health_interval),
+        }
+    }
 }
 
 impl Client {
-    pub fn new(address: SocketAddr) -> Self {
-        Self {
-            address,
-            connection: None,
+    /// Creates a client of the server at a TCP address, or a Unix socket path written `unix:/path`
+    pub fn new(address: impl Into<Address>) -> Self {
+        Self::builder(address).build()
+    }
+
+    pub fn builder(address: impl Into<Address>) -> ClientBuilder {
+        ClientBuilder {
+            address: address.into(),
+            tls: None,
+            codec: CodecKind::Bincode,
+            credentials: None,
+            timeout: Some(DEFAULT_TIMEOUT),
+            retry_policy: RetryPolicy::default(),
+            pool_size: DEFAULT_POOL_SIZE,
+            health_interval: Some(DEFAULT_HEALTH_INTERVAL),
+        }
+    }
+
+    /// Opens the pooled connections; otherwise they are opened on first use
+    pub async fn connect(&self) -> ClientResult<()> {
+        self.pool.connect().await
+    }
+
+    /// Checks that the server is reachable
+    pub async fn ping(&self) -> ClientResult<()> {
+        match self.send_request(Request::Ping).await? {
+            Response::Pong => Ok(()),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Gets the value of a key, or None if the key doesn't exist
+    pub async fn get(&self, key: &str) -> ClientResult<Option<String>> {
+        match self.send_request(Request::Get { key: key.to_owned() }).await? {
+            Response::Get { val } => Ok(Some(val)),
+            Response::Error(e) if e.kind == ErrorKind::NotFound => Ok(None),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Sets the value of a key
+    pub async fn set(&self, key: &str, val: &str) -> ClientResult<()> {
+        match self.send_request(Request::Set { key: key.to_owned(), val: val.to_owned() }).await? {
+            Response::Set => Ok(()),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Deletes a key; returns whether it existed
+    pub async fn delete(&self, key: &str) -> ClientResult<bool> {
+        match self.send_request(Request::Delete { key: key.to_owned() }).await? {
+            Response::Delete { existed } => Ok(existed),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Gets the server statistics
+    pub async fn stats(&self) -> ClientResult<StatsReport> {
+        match self.send_request(Request::Stats).await? {
+            Response::Stats { stats } => Ok(stats),
+            response => Err(ClientError::unexpected(response)),
+        }
+    }
+
+    /// Resets the server statistics; returns when they were reset, in seconds since the Unix epoch
+    pub async fn reset_stats(&self) -> ClientResult<u64> {
+        match self.send_request(Request::ResetStats).await? {
+            Response::ResetStats { reset_at } => Ok(reset_at),
+            response => Err(ClientError::unexpected(response)),
         }
     }
 
-    pub async fn connect(&mut self) -> ClientResult<()> {
-        self.connection = Some(Connection::from_address(self.address).await?);
-        Ok(())
+    /// Queries the server's audit log of mutating operations; requires admin permission
+    pub async fn audit(&self, query: AuditQuery) -> ClientResult<Vec<AuditEntry>> {
+        match self.send_request(Request::Audit { query }).await? {
+            Response::Audit { entries } => Ok(entries),
+            response => Err(ClientError::unexpected(response)),
+        }
     }
 
-    // Sends a request to the server and waits for a response
-    pub async fn send_request(&mut self, request: Request) -> ClientResult<Response> {
-        if self.connection.is_none() {
-            self.connect().await?;
+    /// Sends a raw request to the server and waits for its response, retrying per the retry policy
+    /// Failures reported by the server are returned as responses; prefer the typed methods.
+    pub async fn send_request(&self, request: Request) -> ClientResult<Response> {
+        let mut retry = 0;
+
+        loop {
+            let result = self.attempt(request.clone()).await;
+
+            let retry_after = match retry_after(&request, &result) {
+                Some(retry_after) if retry < self.retry_policy.max_retries && retry_after <= self.retry_policy.max_backoff => retry_after,
+                _ => return result,
+            };
+
+            tokio::time::sleep(self.retry_policy.backoff(retry, retry_after)).await;
+            retry += 1;
         }
+    }
+
+    // Sends a request once, reconnecting first if the connection was lost
+    // The timeout covers reconnecting, and the server is given what is left of it.
+    async fn attempt(&self, request: Request) -> ClientResult<Response> {
+        let Some(timeout) = self.timeout else {
+            return self.pool.get().await?.request(request, None).await;
+        };
 
-        let response = self.connection.as_mut().unwrap()
-            .request(request)
-            .await?
-            .unwrap_or_default();
+        let deadline = Instant::now() + timeout;
+        let attempt = async {
+            let connection = self.pool.get().await?;
+            connection.request(request, Some(deadline.saturating_duration_since(Instant::now()))).await
+        };
 
-        Ok(response)
+        tokio::time::timeout(timeout, attempt)
+            .await
+            .map_err(|_| ClientError::Timeout(timeout))?
     }
 }
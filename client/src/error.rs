@@ -0,0 +1,63 @@
+use std::fmt;
+use std::time::Duration;
+
+use common::dto::{Error, Response};
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+/// Failure of a client call
+#[derive(Debug)]
+pub enum ClientError {
+    /// The server processed the request and reported a failure
+    Server(Error),
+    /// The client is over its rate limit and may retry after the given delay
+    RateLimited { retry_after: Duration },
+    /// No response came back in time
+    Timeout(Duration),
+    /// The server answered with a response that doesn't match the request
+    Protocol(String),
+    /// The connection couldn't be opened or was lost before the request was sent
+    Connection(anyhow::Error),
+    /// The connection was lost after the request was sent; it may have been processed
+    Disconnected,
+}
+
+impl ClientError {
+    /// Turns a response that isn't the expected one into an error
+    pub(crate) fn unexpected(response: Response) -> Self {
+        match response {
+            Response::Error(e) => ClientError::Server(e),
+            Response::RateLimited { retry_after_ms } => ClientError::RateLimited { retry_after: Duration::from_millis(retry_after_ms) },
+            response => ClientError::Protocol(format!("unexpected response {:?}", response)),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Server(e) => write!(f, "server error: {}", e),
+            ClientError::RateLimited { retry_after } => write!(f, "rate limited, retry after {:?}", retry_after),
+            ClientError::Timeout(timeout) => write!(f, "request timed out after {:?}", timeout),
+            ClientError::Protocol(message) => write!(f, "protocol error: {}", message),
+            ClientError::Connection(e) => write!(f, "connection error: {}", e),
+            ClientError::Disconnected => write!(f, "connection lost while waiting for the response"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Server(e) => Some(e),
+            ClientError::Connection(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for ClientError {
+    fn from(e: anyhow::Error) -> Self {
+        ClientError::Connection(e)
+    }
+}
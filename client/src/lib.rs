@@ -1,2 +1,12 @@
+pub mod blocking;
 mod client;
+mod error;
+mod mux;
+mod pool;
+mod retry;
 pub use crate::client::*;
+pub use crate::error::*;
+pub use crate::retry::RetryPolicy;
+pub use common::codec::CodecKind;
+pub use common::net::Address;
+pub use common::tls::TlsClient;
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use tokio::sync::{mpsc, oneshot};
+
+use common::dto::{FrameId, Hello, HelloReply, Request, RequestFrame, Response, ResponseFrame, Token, CONNECTION_FRAME_ID};
+use common::codec::{Bincode, Cbor, Codec, CodecKind, Json, MsgPack};
+use common::net::{Address, Connection, ConnectionWriter, Listener};
+use common::tls::TlsClient;
+
+use crate::{ClientError, ClientResult};
+
+// Maximum number of requests waiting to be written to the connection
+const QUEUE_DEPTH: usize = 64;
+
+type Reply = oneshot::Sender<ClientResult<Response>>;
+
+// A request waiting to be sent
+struct Queued {
+    request: Request,
+    timeout: Option<Duration>,
+    reply: Reply,
+}
+
+// Where and how to connect to the server
+#[derive(Clone)]
+pub(crate) struct Endpoint {
+    pub address: Address,
+    pub tls: Option<TlsClient>,
+    pub codec: CodecKind,
+    // user and token to authenticate with, for servers requiring it
+    pub credentials: Option<(String, Token)>,
+}
+
+// Shares a single connection between concurrent requests
+// A background task owns the connection: it tags outgoing requests with ids
+// and routes each response back to its caller by id.
+#[derive(Clone)]
+pub(crate) struct Multiplexer {
+    requests: mpsc::Sender<Queued>,
+    // connection-level response sent by the server before closing, e.g. a refused connection
+    failure: Arc<OnceLock<Response>>,
+}
+
+impl Multiplexer {
+    pub async fn connect(endpoint: &Endpoint) -> ClientResult<Self> {
+        match endpoint.codec {
+            CodecKind::Json => Self::connect_with::<Json>(endpoint).await,
+            CodecKind::Bincode => Self::connect_with::<Bincode>(endpoint).await,
+            CodecKind::MsgPack => Self::connect_with::<MsgPack>(endpoint).await,
+            CodecKind::Cbor => Self::connect_with::<Cbor>(endpoint).await,
+        }
+    }
+
+    async fn connect_with<C: Codec>(endpoint: &Endpoint) -> ClientResult<Self> {
+        let mut connection = match &endpoint.tls {
+            Some(tls) => Connection::<C>::from_address_tls(endpoint.address.clone(), tls).await?,
+            None => Connection::<C>::from_address(endpoint.address.clone()).await?,
+        };
+        let max_pipelined = handshake(&mut connection).await?;
+
+        if let Some((user, token)) = &endpoint.credentials {
+            authenticate(&mut connection, user, token).await?;
+        }
+
+        let (requests, queued) = mpsc::channel(QUEUE_DEPTH);
+        let failure = Arc::new(OnceLock::new());
+        tokio::spawn(run(connection, queued, max_pipelined, failure.clone()));
+
+        Ok(Self { requests, failure })
+    }
+
+    pub async fn request(&self, request: Request, timeout: Option<Duration>) -> ClientResult<Response> {
+        let (reply, response) = oneshot::channel();
+
+        if self.requests.send(Queued { request, timeout, reply }).await.is_err() {
+            return self.closed();
+        }
+
+        match response.await {
+            Ok(response) => response,
+            Err(_) => self.closed(),
+        }
+    }
+
+    /// Whether the connection is gone, so that requests would fail
+    pub fn is_closed(&self) -> bool {
+        self.requests.is_closed()
+    }
+
+    /// Whether both handles share the same connection
+    pub fn same_connection(&self, other: &Multiplexer) -> bool {
+        self.requests.same_channel(&other.requests)
+    }
+
+    fn closed(&self) -> ClientResult<Response> {
+        self.failure
+            .get()
+            .cloned()
+            .ok_or_else(|| ClientError::Connection(io::Error::new(io::ErrorKind::NotConnected, "connection closed").into()))
+    }
+}
+
+// Introduces the client to the server and checks that it can be served
+// Returns how many requests the server accepts in flight at once.
+async fn handshake<C: Codec>(connection: &mut Connection<C>) -> ClientResult<usize> {
+    connection.respond(Hello::new(&[C::NAME])).await?;
+
+    match connection.listen::<HelloReply>().await? {
+        Some(HelloReply::Accepted { max_pipelined, .. }) => Ok(usize::try_from(max_pipelined).unwrap_or(usize::MAX).max(1)),
+        Some(HelloReply::Rejected { reason }) => Err(anyhow!("connection rejected: {}", reason).into()),
+        None => Err(anyhow!("connection closed during handshake; the server may not support protocol versioning").into()),
+    }
+}
+
+// Authenticates the connection, before it is shared
+async fn authenticate<C: Codec>(connection: &mut Connection<C>, user: &str, token: &Token) -> ClientResult<()> {
+    let request = Request::Auth { user: user.to_owned(), token: token.clone() };
+    connection.respond(RequestFrame { id: CONNECTION_FRAME_ID + 1, timeout_ms: None, request }).await?;
+
+    match connection.listen::<ResponseFrame>().await? {
+        Some(ResponseFrame { response: Response::Auth, .. }) => Ok(()),
+        Some(ResponseFrame { response, .. }) => Err(ClientError::unexpected(response)),
+        None => Err(anyhow!("connection closed during authentication").into()),
+    }
+}
+
+// Sends queued requests and routes their responses, until the connection or all handles are gone
+// Requests are written by their own task, so that responses are still read while the server
+// is slow to read the requests, and at most max_pipelined of them are in flight at once.
+async fn run<C: Codec>(connection: Connection<C>, mut queued: mpsc::Receiver<Queued>, max_pipelined: usize, failure: Arc<OnceLock<Response>>) {
+    let mut next_id = CONNECTION_FRAME_ID + 1;
+    let mut in_flight = HashMap::<u64, Reply>::new();
+
+    let (mut reader, writer) = connection.split();
+    // requests stay in flight until answered, so the channel never fills up
+    let (frames, outgoing) = mpsc::channel::<RequestFrame>(max_pipelined);
+    let writer = tokio::spawn(write_requests(writer, outgoing));
+
+    loop {
+        tokio::select! {
+            request = queued.recv(), if in_flight.len() < max_pipelined => {
+                // all handles are gone
+                let Some(Queued { request, timeout, reply }) = request else { break };
+
+                let id = next_id;
+                next_id += 1;
+
+                let timeout_ms = timeout.map(|t| u64::try_from(t.as_millis()).unwrap_or(u64::MAX));
+                let frame = RequestFrame { id, timeout_ms, request };
+
+                // registered first, as the response may come before the write completes
+                in_flight.insert(id, reply);
+                if frames.send(frame).await.is_err() {
+                    break;
+                }
+            },
+            // a failed write may still have delivered the request
+            _ = frames.closed() => break,
+            received = reader.listen_with_fallback::<ResponseFrame, FrameId>() => {
+                match received {
+                    // connection-level failures end the connection, and apply to every request in flight
+                    Ok(Some(Ok(ResponseFrame { id: CONNECTION_FRAME_ID, response }))) => {
+                        for (_, reply) in in_flight.drain() {
+                            _ = reply.send(Ok(response.clone()));
+                        }
+                        _ = failure.set(response);
+                        break;
+                    },
+                    Ok(Some(Ok(ResponseFrame { id, response }))) => {
+                        // the caller may have timed out in the meantime
+                        if let Some(reply) = in_flight.remove(&id) {
+                            _ = reply.send(Ok(response));
+                        }
+                    },
+                    // e.g. a response unknown to this client, from a newer server
+                    Ok(Some(Err((FrameId { id }, e)))) if id != CONNECTION_FRAME_ID => {
+                        if let Some(reply) = in_flight.remove(&id) {
+                            _ = reply.send(Err(ClientError::Protocol(format!("undecodable response: {}", e))));
+                        }
+                    },
+                    _ => break,
+                }
+            },
+        }
+    }
+
+    writer.abort();
+
+    // requests sent without an answer may have been processed, unlike the ones still queued
+    if failure.get().is_none() {
+        for (_, reply) in in_flight {
+            _ = reply.send(Err(ClientError::Disconnected));
+        }
+    }
+}
+
+// Writes the requests of a connection in order, until a write fails
+async fn write_requests<C: Codec>(mut writer: ConnectionWriter<C>, mut outgoing: mpsc::Receiver<RequestFrame>) {
+    while let Some(frame) = outgoing.recv().await {
+        if writer.respond(frame).await.is_err() {
+            break;
+        }
+    }
+}
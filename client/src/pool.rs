@@ -0,0 +1,100 @@
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+
+use common::dto::{Request, Response};
+
+use crate::error::ClientResult;
+use crate::mux::{Endpoint, Multiplexer};
+
+// Fixed set of connections to the server, handed out in turn
+// Each connection pipelines the requests of all its users. Closed connections are
+// replaced when next handed out, and a background task drops the ones failing health checks.
+pub(crate) struct Pool {
+    endpoint: Endpoint,
+    slots: Vec<Mutex<Option<Multiplexer>>>,
+    next: AtomicUsize,
+    health_interval: Option<Duration>,
+    health_started: AtomicBool,
+}
+
+impl Pool {
+    pub fn new(endpoint: Endpoint, size: usize, health_interval: Option<Duration>) -> Arc<Self> {
+        Arc::new(Self {
+            endpoint,
+            slots: (0..size.max(1)).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+            health_interval,
+            health_started: AtomicBool::new(false),
+        })
+    }
+
+    /// Opens every connection of the pool
+    pub async fn connect(self: &Arc<Self>) -> ClientResult<()> {
+        self.start_health_checks();
+
+        for slot in &self.slots {
+            let mut slot = slot.lock().await;
+            if slot.as_ref().is_none_or(|mux| mux.is_closed()) {
+                *slot = Some(Multiplexer::connect(&self.endpoint).await?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hands out the next connection, reconnecting it if needed
+    pub async fn get(self: &Arc<Self>) -> ClientResult<Multiplexer> {
+        self.start_health_checks();
+
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        let mut slot = self.slots[i].lock().await;
+
+        if let Some(mux) = slot.as_ref().filter(|mux| !mux.is_closed()) {
+            return Ok(mux.clone());
+        }
+
+        let mux = Multiplexer::connect(&self.endpoint).await?;
+        *slot = Some(mux.clone());
+
+        Ok(mux)
+    }
+
+    // The health checks run on the caller's runtime, so they start with the first connection
+    fn start_health_checks(self: &Arc<Self>) {
+        let Some(interval) = self.health_interval else { return };
+
+        if !self.health_started.swap(true, Ordering::Relaxed) {
+            tokio::spawn(check_health(Arc::downgrade(self), interval));
+        }
+    }
+}
+
+// Pings every open connection periodically and drops the ones not answering in time
+// Stops once the pool is gone.
+async fn check_health(pool: Weak<Pool>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        let Some(pool) = pool.upgrade() else { break };
+
+        for slot in &pool.slots {
+            // connections being opened are skipped
+            let Some(mux) = slot.try_lock().ok().and_then(|slot| slot.clone()) else { continue };
+
+            let pinged = tokio::time::timeout(interval, mux.request(Request::Ping, Some(interval))).await;
+            let healthy = matches!(pinged, Ok(Ok(Response::Pong)));
+
+            if !healthy {
+                let mut slot = slot.lock().await;
+                if slot.as_ref().is_some_and(|current| current.same_connection(&mux)) {
+                    *slot = None;
+                }
+            }
+        }
+    }
+}
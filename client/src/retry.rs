@@ -0,0 +1,94 @@
+use std::io;
+use std::time::Duration;
+
+use rand::Rng;
+
+use common::dto::{ErrorKind, Request, Response};
+
+use crate::error::ClientError;
+
+/// How failed requests are retried
+///
+/// Requests are retried when they certainly weren't processed, e.g. while the server restarts
+/// or when a write conflicted with a concurrent one. Connections the server refuses,
+/// e.g. for a rejected handshake or TLS certificate, aren't retried.
+/// Idempotent requests (gets, stats, pings and audit queries) are also retried when their outcome is unknown,
+/// e.g. when the connection dropped or the request timed out.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; 0 disables retrying
+    pub max_retries: u32,
+    /// Delay before the first retry
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between retries
+    pub max_backoff: Duration,
+    /// Factor applied to the delay after each retry
+    pub multiplier: f64,
+    /// Randomizes each delay between half and all of its value, so clients don't retry in lockstep
+    pub jitter: bool,
+}
+
+impl RetryPolicy {
+    /// Never retries
+    pub fn none() -> Self {
+        Self { max_retries: 0, ..Self::default() }
+    }
+
+    // Delay before the given retry, starting at 0
+    // The server's retry-after hint wins when it is longer.
+    pub(crate) fn backoff(&self, retry: u32, retry_after: Duration) -> Duration {
+        let exponential = self.initial_backoff.as_secs_f64() * self.multiplier.powi(retry as i32);
+        let mut delay = Duration::from_secs_f64(exponential.min(self.max_backoff.as_secs_f64()));
+
+        if self.jitter && !delay.is_zero() {
+            delay = rand::thread_rng().gen_range(delay / 2..=delay);
+        }
+
+        retry_after.max(delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: true,
+        }
+    }
+}
+
+// Whether repeating the request gives the same result as sending it once
+fn is_idempotent(request: &Request) -> bool {
+    matches!(request, Request::Get { .. } | Request::Stats | Request::Ping | Request::Audit { .. })
+}
+
+// Whether a connection failure comes from the transport, e.g. while the server restarts
+// Rejected handshakes, TLS failures and closed authentications would fail the same way again.
+fn is_transport_failure(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| matches!(e.kind(),
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe | io::ErrorKind::UnexpectedEof | io::ErrorKind::NotConnected
+            | io::ErrorKind::TimedOut | io::ErrorKind::AddrNotAvailable | io::ErrorKind::NotFound))
+}
+
+// Whether a failed attempt can be retried, and if so the minimum delay asked by the server
+pub(crate) fn retry_after(request: &Request, result: &Result<Response, ClientError>) -> Option<Duration> {
+    let idempotent = is_idempotent(request);
+
+    match result {
+        // the server refused to process the request
+        Ok(Response::RateLimited { retry_after_ms }) => Some(Duration::from_millis(*retry_after_ms)),
+        Ok(Response::Error(e)) if e.kind == ErrorKind::Conflict => Some(Duration::ZERO),
+        Ok(Response::Error(e)) if e.kind == ErrorKind::Timeout && idempotent => Some(Duration::ZERO),
+        // the request never left the client, and the server may be back soon
+        Err(ClientError::Connection(e)) if is_transport_failure(e) => Some(Duration::ZERO),
+        // the request may have been processed
+        Err(ClientError::Disconnected | ClientError::Timeout(_)) if idempotent => Some(Duration::ZERO),
+        _ => None,
+    }
+}
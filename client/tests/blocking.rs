@@ -0,0 +1,30 @@
+mod common;
+
+use std::{net::SocketAddr, str::FromStr};
+
+use client::blocking::Client;
+use ::common::dto::{Request, Response};
+
+#[test]
+fn test_blocking_client() {
+    let address = SocketAddr::from_str("127.0.0.1:8137").unwrap();
+
+    // the fake server runs on its own runtime, the test itself is synchronous
+    let runtime = tokio::runtime::Runtime::new().expect("failed runtime");
+    runtime.block_on(common::serve(address, |_, frame| async move {
+        match frame.request {
+            Request::Get { key } => Some(Response::Get { val: key }),
+            Request::Delete { .. } => Some(Response::Delete { existed: true }),
+            _ => Some(Response::Set),
+        }
+    }));
+
+    let c = Client::builder(address)
+        .pool_size(1)
+        .build_blocking()
+        .expect("failed client");
+
+    c.set("a", "b").expect("failed set");
+    assert_eq!(c.get("a").expect("failed get").as_deref(), Some("a"), "bad get");
+    assert!(c.clone().delete("a").expect("failed delete"), "bad delete");
+}
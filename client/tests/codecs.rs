@@ -0,0 +1,28 @@
+mod common;
+
+use std::{net::SocketAddr, str::FromStr};
+
+use client::{Client, CodecKind};
+use ::common::dto::{Request, Response};
+
+#[tokio::test]
+async fn test_codec_selection() {
+    let address = SocketAddr::from_str("127.0.0.1:8143").unwrap();
+
+    // answers with the codec it detected
+    common::serve(address, |peer, frame| async move {
+        assert!(matches!(frame.request, Request::Get { .. }), "bad request");
+        Some(Response::Get { val: peer.codec.to_owned() })
+    }).await;
+
+    for codec in CodecKind::ALL {
+        let c = Client::builder(address)
+            .codec(codec)
+            .pool_size(1)
+            .no_health_checks()
+            .build();
+
+        let val = c.get("a").await.expect("failed request");
+        assert_eq!(val.as_deref(), Some(codec.name()), "bad {} detection", codec);
+    }
+}
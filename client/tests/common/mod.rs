@@ -0,0 +1,141 @@
+// Fake server shared by the client tests
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::mpsc;
+
+use common::dto::{Hello, HelloReply, RequestFrame, Response, ResponseFrame};
+use common::net::{Address, AnyConnection, Listener, Transport};
+
+/// Number of requests the fake server accepts in flight at once, announced in its handshake
+pub const MAX_PIPELINED: u32 = 4;
+
+/// Connection served by the fake server
+#[derive(Clone, Copy, Debug)]
+pub struct Peer {
+    /// Order in which the connection was accepted, from 0
+    pub index: usize,
+    /// Codec chosen by the client
+    pub codec: &'static str,
+}
+
+/// Serves every connection to an address with any codec: completes the handshake,
+/// then answers each request with `respond`, concurrently; answering None closes the connection.
+/// Returns the number of accepted connections.
+pub async fn serve<F, Fut>(address: impl Into<Address>, respond: F) -> Arc<AtomicUsize>
+where
+    F: Fn(Peer, RequestFrame) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Option<Response>> + Send + 'static,
+{
+    let respond = Arc::new(respond);
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+
+    match address.into() {
+        Address::Tcp(address) => {
+            let listener = TcpListener::bind(address)
+                .await
+                .expect("failed bind");
+
+            tokio::spawn(async move {
+                loop {
+                    let (socket, _) = listener.accept()
+                        .await
+                        .expect("failed accept");
+
+                    let index = counter.fetch_add(1, Ordering::Relaxed);
+                    tokio::spawn(serve_connection(Transport::Tcp(socket), index, respond.clone()));
+                }
+            });
+        },
+        #[cfg(unix)]
+        Address::Unix(path) => {
+            _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path)
+                .expect("failed bind");
+
+            tokio::spawn(async move {
+                loop {
+                    let (socket, _) = listener.accept()
+                        .await
+                        .expect("failed accept");
+
+                    let index = counter.fetch_add(1, Ordering::Relaxed);
+                    tokio::spawn(serve_connection(Transport::Unix(socket), index, respond.clone()));
+                }
+            });
+        },
+        #[cfg(not(unix))]
+        Address::Unix(_) => panic!("no Unix sockets on this platform"),
+    }
+
+    accepted
+}
+
+async fn serve_connection<S, F, Fut>(transport: S, index: usize, respond: Arc<F>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(Peer, RequestFrame) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Option<Response>> + Send + 'static,
+{
+    let connection = AnyConnection::accept(transport)
+        .await
+        .expect("failed detection");
+
+    let peer = Peer { index, codec: connection.codec() };
+
+    match connection {
+        AnyConnection::Json(s) => answer(s, peer, respond).await,
+        AnyConnection::Bincode(s) => answer(s, peer, respond).await,
+        AnyConnection::MsgPack(s) => answer(s, peer, respond).await,
+        AnyConnection::Cbor(s) => answer(s, peer, respond).await,
+    }
+}
+
+async fn answer<L, F, Fut>(mut s: L, peer: Peer, respond: Arc<F>)
+where
+    L: Listener + Send,
+    F: Fn(Peer, RequestFrame) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Option<Response>> + Send + 'static,
+{
+    let hello = s.listen::<Hello>()
+        .await
+        .expect("no hello")
+        .expect("empty hello");
+
+    assert!(hello.codecs.iter().any(|c| c == peer.codec), "bad hello codecs {:?}", hello.codecs);
+
+    let reply = HelloReply::Accepted { version: hello.version, codec: peer.codec.to_owned(), features: hello.features, max_pipelined: MAX_PIPELINED };
+    s.respond(reply)
+        .await
+        .expect("failed hello reply");
+
+    let (responses, mut completed) = mpsc::unbounded_channel::<Option<ResponseFrame>>();
+
+    loop {
+        tokio::select! {
+            frame = s.listen::<RequestFrame>() => {
+                let Ok(Some(frame)) = frame else { break };
+
+                let (respond, responses) = (respond.clone(), responses.clone());
+                tokio::spawn(async move {
+                    let id = frame.id;
+                    let response = respond(peer, frame).await;
+                    _ = responses.send(response.map(|response| ResponseFrame { id, response }));
+                });
+            },
+            Some(frame) = completed.recv() => {
+                let Some(frame) = frame else { break };
+                if s.respond(frame).await.is_err() {
+                    break;
+                }
+            },
+        }
+    }
+}
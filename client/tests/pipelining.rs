@@ -0,0 +1,87 @@
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::{net::SocketAddr, str::FromStr};
+use tokio::sync::Barrier;
+
+use client::Client;
+use ::common::dto::{Request, Response};
+
+#[tokio::test]
+async fn test_pipelined_out_of_order() {
+    let address = SocketAddr::from_str("127.0.0.1:8130").unwrap();
+
+    // answers the requests only once all of them arrived, in reverse order
+    let arrived = Arc::new(Barrier::new(3));
+    common::serve(address, move |_, frame| {
+        let arrived = arrived.clone();
+        async move {
+            let Request::Get { key } = frame.request else { panic!("bad request") };
+            arrived.wait().await;
+
+            let delay = match key.as_str() {
+                "a" => 200,
+                "b" => 100,
+                _ => 0,
+            };
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+
+            Some(Response::Get { val: key })
+        }
+    }).await;
+
+    // the fake server serves a single connection
+    let c = Client::builder(address)
+        .pool_size(1)
+        .build();
+
+    let (a, b, d) = tokio::join!(c.get("a"), c.get("b"), c.get("c"));
+
+    for (val, key) in [(a, "a"), (b, "b"), (d, "c")] {
+        assert_eq!(val.expect("failed request").as_deref(), Some(key), "mismatched response");
+    }
+}
+
+#[tokio::test]
+async fn test_pipelining_limit() {
+    let address = SocketAddr::from_str("127.0.0.1:8144").unwrap();
+
+    // records the most requests seen in flight at once
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let most = Arc::new(AtomicUsize::new(0));
+    let (counter, highest) = (in_flight.clone(), most.clone());
+    common::serve(address, move |_, frame| {
+        let (in_flight, most) = (counter.clone(), highest.clone());
+        async move {
+            let Request::Get { key } = frame.request else { panic!("bad request") };
+
+            let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(current, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+
+            Some(Response::Get { val: key })
+        }
+    }).await;
+
+    let c = Client::builder(address)
+        .pool_size(1)
+        .build();
+
+    let requests = (0..20)
+        .map(|i| {
+            let c = c.clone();
+            tokio::spawn(async move { (i.to_string(), c.get(&i.to_string()).await) })
+        })
+        .collect::<Vec<_>>();
+
+    for request in requests {
+        let (key, val) = request.await.expect("failed task");
+        assert_eq!(val.expect("failed request"), Some(key), "mismatched response");
+    }
+
+    let most = most.load(Ordering::SeqCst);
+    assert!(most <= common::MAX_PIPELINED as usize, "bad pipelining limit, {} requests in flight", most);
+}
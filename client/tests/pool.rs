@@ -0,0 +1,40 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::{net::SocketAddr, str::FromStr};
+
+use client::Client;
+use ::common::dto::{Request, Response};
+
+#[tokio::test]
+async fn test_shared_pool() {
+    let address = SocketAddr::from_str("127.0.0.1:8134").unwrap();
+
+    // echoes the keys back, on any number of connections
+    let accepted = common::serve(address, |_, frame| async move {
+        match frame.request {
+            Request::Get { key } => Some(Response::Get { val: key }),
+            _ => Some(Response::Pong),
+        }
+    }).await;
+
+    let c = Client::builder(address)
+        .pool_size(2)
+        .build();
+
+    c.ping().await.expect("failed ping");
+
+    let tasks = (0..8)
+        .map(|i| {
+            let c = c.clone();
+            tokio::spawn(async move { (i, c.get(&i.to_string()).await) })
+        })
+        .collect::<Vec<_>>();
+
+    for task in tasks {
+        let (i, val) = task.await.expect("failed task");
+        assert_eq!(val.expect("failed get"), Some(i.to_string()), "mismatched response");
+    }
+
+    assert_eq!(accepted.load(Ordering::Relaxed), 2, "bad connection count");
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use std::{net::SocketAddr, str::FromStr};
+use tokio::net::TcpListener;
+
+use client::{Client, ClientError};
+use ::common::dto::{Error, ErrorKind, Hello, HelloReply, Request, RequestFrame, Response, ResponseFrame};
+use ::common::net::{BincodeConnection, Listener};
+
+#[tokio::test]
+async fn test_typed_responses() {
+    let address = SocketAddr::from_str("127.0.0.1:8133").unwrap();
+
+    // answers gets of "missing" as not found, and everything else with the wrong response kind
+    common::serve(address, |_, frame| async move {
+        match frame.request {
+            Request::Get { key } if key == "missing" => Some(Response::Error(Error::new(ErrorKind::NotFound, "not found"))),
+            Request::Set { .. } => Some(Response::Error(Error::new(ErrorKind::Internal, "disk full"))),
+            _ => Some(Response::Set),
+        }
+    }).await;
+
+    let c = Client::builder(address)
+        .pool_size(1)
+        .build();
+
+    let val = c.get("missing").await.expect("failed get");
+    assert_eq!(val, None, "bad missing key");
+
+    match c.get("other").await {
+        Err(ClientError::Protocol(_)) => (),
+        r => panic!("mismatched response accepted {:?}", r),
+    }
+
+    match c.set("key", "val").await {
+        Err(ClientError::Server(e)) => assert_eq!(e.kind, ErrorKind::Internal, "bad error kind"),
+        r => panic!("server error not reported {:?}", r),
+    }
+}
+
+#[tokio::test]
+async fn test_unknown_response() {
+    let address = "127.0.0.1:8147";
+
+    let listener = TcpListener::bind(address)
+        .await
+        .expect("failed bind");
+
+    // answers gets with a response from a newer protocol, and everything else as usual
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept()
+            .await
+            .expect("failed accept");
+
+        let mut s = BincodeConnection::from_socket(socket);
+
+        let hello = s.listen::<Hello>()
+            .await
+            .expect("no hello")
+            .expect("empty hello");
+
+        let reply = HelloReply::Accepted { version: hello.version, codec: BincodeConnection::CODEC.to_owned(), features: hello.features, max_pipelined: 64 };
+        s.respond(reply)
+            .await
+            .expect("failed hello reply");
+
+        while let Ok(Some(frame)) = s.listen::<RequestFrame>().await {
+            let sent = match frame.request {
+                // the frame id followed by a response variant unknown to the client
+                Request::Get { .. } => s.respond((frame.id, u32::MAX)).await,
+                _ => s.respond(ResponseFrame { id: frame.id, response: Response::Set }).await,
+            };
+            sent.expect("failed response");
+        }
+    });
+
+    let c = Client::builder(SocketAddr::from_str(address).unwrap())
+        .pool_size(1)
+        .no_health_checks()
+        .build();
+
+    match c.get("new").await {
+        Err(ClientError::Protocol(_)) => (),
+        r => panic!("unknown response accepted {:?}", r),
+    }
+
+    // only the request with the unknown response failed
+    c.set("key", "val").await.expect("connection dropped");
+}
@@ -0,0 +1,148 @@
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::{net::SocketAddr, str::FromStr};
+use tokio::net::TcpListener;
+
+use client::{Client, ClientError, RetryPolicy};
+use ::common::dto::{Error, ErrorKind, Hello, HelloReply, Request, Response};
+use ::common::net::{BincodeConnection, Listener};
+
+const POLICY: RetryPolicy = RetryPolicy {
+    max_retries: 3,
+    initial_backoff: Duration::from_millis(10),
+    max_backoff: Duration::from_millis(100),
+    multiplier: 2.0,
+    jitter: true,
+};
+
+// Drops the first connection once it received a request, as if the server restarted,
+// then answers on the next ones; returns the number of accepted connections
+async fn flaky_server(address: &str) -> Arc<AtomicUsize> {
+    common::serve(SocketAddr::from_str(address).unwrap(), |peer, frame| async move {
+        if peer.index == 0 {
+            return None;
+        }
+
+        match frame.request {
+            Request::Get { key } => Some(Response::Get { val: key }),
+            _ => Some(Response::Set),
+        }
+    }).await
+}
+
+#[tokio::test]
+async fn test_reconnect_and_retry() {
+    let address = "127.0.0.1:8135";
+    let accepted = flaky_server(address).await;
+
+    let c = Client::builder(SocketAddr::from_str(address).unwrap())
+        .pool_size(1)
+        .retry_policy(POLICY)
+        .build();
+
+    let val = c.get("a").await.expect("failed get");
+    assert_eq!(val.as_deref(), Some("a"), "bad response");
+    assert_eq!(accepted.load(Ordering::Relaxed), 2, "bad connection count");
+}
+
+#[tokio::test]
+async fn test_unsafe_write_not_retried() {
+    let address = "127.0.0.1:8136";
+    let accepted = flaky_server(address).await;
+
+    let c = Client::builder(SocketAddr::from_str(address).unwrap())
+        .pool_size(1)
+        .retry_policy(POLICY)
+        .build();
+
+    // the write reached the server before the connection dropped, so it isn't repeated
+    match c.set("a", "b").await {
+        Err(ClientError::Disconnected) => (),
+        r => panic!("write retried {:?}", r),
+    }
+    assert_eq!(accepted.load(Ordering::Relaxed), 1, "bad connection count");
+
+    // the next write goes over a new connection
+    c.set("a", "b").await.expect("failed set");
+}
+
+#[tokio::test]
+async fn test_conflict_retried() {
+    let address = SocketAddr::from_str("127.0.0.1:8148").unwrap();
+
+    // the first write conflicts with a concurrent one, and isn't applied
+    let writes = Arc::new(AtomicUsize::new(0));
+    let counter = writes.clone();
+    common::serve(address, move |_, _| {
+        let first = counter.fetch_add(1, Ordering::Relaxed) == 0;
+        async move {
+            match first {
+                true => Some(Response::Error(Error::new(ErrorKind::Conflict, "concurrent write"))),
+                false => Some(Response::Set),
+            }
+        }
+    }).await;
+
+    let c = Client::builder(address)
+        .pool_size(1)
+        .retry_policy(POLICY)
+        .build();
+
+    c.set("a", "b").await.expect("conflicting write not retried");
+    assert_eq!(writes.load(Ordering::Relaxed), 2, "bad write count");
+}
+
+#[tokio::test]
+async fn test_refused_connection_retried() {
+    let address = SocketAddr::from_str("127.0.0.1:8149").unwrap();
+
+    // the server starts listening between the first and second retries
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        common::serve(address, |_, _| async { Some(Response::Pong) }).await;
+    });
+
+    let c = Client::builder(address)
+        .pool_size(1)
+        .no_health_checks()
+        .retry_policy(RetryPolicy { initial_backoff: Duration::from_millis(100), max_backoff: Duration::from_millis(400), jitter: false, ..POLICY })
+        .build();
+
+    c.ping().await.expect("refused connection not retried");
+}
+
+#[tokio::test]
+async fn test_rejected_handshake_not_retried() {
+    let address = SocketAddr::from_str("127.0.0.1:8150").unwrap();
+
+    // rejects every connection, as when the server has too many
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    let listener = TcpListener::bind(address)
+        .await
+        .expect("failed bind");
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::Relaxed);
+            let mut s = BincodeConnection::from_socket(socket);
+
+            _ = s.listen::<Hello>().await;
+            _ = s.respond(HelloReply::Rejected { reason: "too many connections".to_owned() }).await;
+        }
+    });
+
+    let c = Client::builder(address)
+        .pool_size(1)
+        .no_health_checks()
+        .retry_policy(POLICY)
+        .build();
+
+    match c.ping().await {
+        Err(ClientError::Connection(_)) => (),
+        r => panic!("rejected connection accepted {:?}", r),
+    }
+    assert_eq!(accepted.load(Ordering::Relaxed), 1, "bad connection count");
+}
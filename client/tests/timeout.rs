@@ -0,0 +1,66 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{net::SocketAddr, str::FromStr};
+use tokio::net::TcpListener;
+
+use client::{Client, ClientError, RetryPolicy};
+
+const TIMEOUT: Duration = Duration::from_millis(300);
+
+#[tokio::test]
+async fn test_stalled_handshake() {
+    let address = SocketAddr::from_str("127.0.0.1:8145").unwrap();
+
+    // accepts connections but never answers the Hello
+    let listener = TcpListener::bind(address)
+        .await
+        .expect("failed bind");
+    tokio::spawn(async move {
+        let mut sockets = Vec::new();
+        while let Ok((socket, _)) = listener.accept().await {
+            sockets.push(socket);
+        }
+    });
+
+    let c = Client::builder(address)
+        .pool_size(1)
+        .timeout(TIMEOUT)
+        .retry_policy(RetryPolicy::none())
+        .build();
+
+    let started = Instant::now();
+    let result = c.get("a").await;
+
+    assert!(matches!(result, Err(ClientError::Timeout(t)) if t == TIMEOUT), "bad result {:?}", result);
+    assert!(started.elapsed() < TIMEOUT * 3, "timed out late, after {:?}", started.elapsed());
+}
+
+#[tokio::test]
+async fn test_stalled_request() {
+    let address = SocketAddr::from_str("127.0.0.1:8146").unwrap();
+
+    // records the time budget of each request and never answers
+    let budgets = Arc::new(Mutex::new(Vec::new()));
+    let recorded = budgets.clone();
+    common::serve(address, move |_, frame| {
+        recorded.lock().unwrap().push(frame.timeout_ms);
+        std::future::pending()
+    }).await;
+
+    let c = Client::builder(address)
+        .pool_size(1)
+        .timeout(TIMEOUT)
+        .retry_policy(RetryPolicy::none())
+        .build();
+
+    let result = c.get("a").await;
+    assert!(matches!(result, Err(ClientError::Timeout(t)) if t == TIMEOUT), "bad result {:?}", result);
+
+    // the server is given what is left of the client's timeout after connecting
+    let budgets = budgets.lock().unwrap().clone();
+    assert_eq!(budgets.len(), 1, "bad request count");
+    let budget = budgets[0].expect("no time budget");
+    assert!(budget > 0 && u128::from(budget) <= TIMEOUT.as_millis(), "bad time budget {}", budget);
+}
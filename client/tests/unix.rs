@@ -0,0 +1,30 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::str::FromStr;
+
+use client::{Address, Client};
+use ::common::dto::{Request, Response};
+
+#[tokio::test]
+async fn test_unix_socket() {
+    let path = std::env::temp_dir().join(format!("dict-client-{}.sock", std::process::id()));
+
+    let address = Address::from_str(&format!("unix:{}", path.display())).expect("bad address");
+    assert_eq!(address, Address::Unix(path.clone()), "bad parsed address");
+
+    common::serve(address.clone(), |_, frame| async move {
+        let Request::Get { key } = frame.request else { panic!("bad request") };
+        Some(Response::Get { val: key })
+    }).await;
+
+    let c = Client::builder(address)
+        .pool_size(1)
+        .build();
+
+    let val = c.get("a").await.expect("failed request");
+    assert_eq!(val.as_deref(), Some("a"), "bad response");
+
+    _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, de::DeserializeOwned};
+
+/// Encoding of the messages carried by a connection, one message per frame
+/// A new codec only needs this trait to be used by connections.
+pub trait Codec: Send + Sync + 'static {
+    /// Codec name, as used in the handshake
+    const NAME: &'static str;
+
+    /// Encodes a message into a frame payload
+    fn encode<M: Serialize>(message: &M) -> anyhow::Result<Vec<u8>>;
+
+    /// Decodes a frame payload into a message
+    fn decode<M: DeserializeOwned>(frame: &[u8]) -> Result<M, DecodeError>;
+}
+
+/// JSON messages, readable by clients in any language
+pub struct Json;
+
+impl Codec for Json {
+    const NAME: &'static str = "json";
+
+    fn encode<M: Serialize>(message: &M) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(message)?)
+    }
+
+    fn decode<M: DeserializeOwned>(frame: &[u8]) -> Result<M, DecodeError> {
+        Ok(serde_json::from_slice(frame)?)
+    }
+}
+
+/// Bincode messages, compact and fast for Rust peers
+/// See https://blog.logrocket.com/rust-serialization-whats-ready-for-production-today/
+pub struct Bincode;
+
+impl Codec for Bincode {
+    const NAME: &'static str = "bincode";
+
+    // frames hold the message wrapped in a bincode byte sequence, as sent by earlier versions
+    fn encode<M: Serialize>(message: &M) -> anyhow::Result<Vec<u8>> {
+        Ok(bincode::serialize(&bincode::serialize(message)?)?)
+    }
+
+    fn decode<M: DeserializeOwned>(frame: &[u8]) -> Result<M, DecodeError> {
+        let message: Vec<u8> = bincode::deserialize(frame)?;
+        Ok(bincode::deserialize(&message)?)
+    }
+}
+
+/// MessagePack messages, with structs as maps so that peers in other languages see field names
+pub struct MsgPack;
+
+impl Codec for MsgPack {
+    const NAME: &'static str = "msgpack";
+
+    fn encode<M: Serialize>(message: &M) -> anyhow::Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(message)?)
+    }
+
+    fn decode<M: DeserializeOwned>(frame: &[u8]) -> Result<M, DecodeError> {
+        Ok(rmp_serde::from_slice(frame)?)
+    }
+}
+
+/// CBOR messages, see RFC 8949
+pub struct Cbor;
+
+impl Codec for Cbor {
+    const NAME: &'static str = "cbor";
+
+    fn encode<M: Serialize>(message: &M) -> anyhow::Result<Vec<u8>> {
+        let mut frame = Vec::new();
+        ciborium::into_writer(message, &mut frame)?;
+        Ok(frame)
+    }
+
+    fn decode<M: DeserializeOwned>(frame: &[u8]) -> Result<M, DecodeError> {
+        ciborium::from_reader(frame).map_err(|e| DecodeError(e.to_string()))
+    }
+}
+
+/// Codec choice, e.g. in a client or server configuration
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CodecKind {
+    Json,
+    Bincode,
+    MsgPack,
+    Cbor,
+}
+
+impl CodecKind {
+    /// All the supported codecs
+    pub const ALL: [CodecKind; 4] = [CodecKind::Json, CodecKind::Bincode, CodecKind::MsgPack, CodecKind::Cbor];
+
+    /// Codec name, as used in the handshake
+    pub fn name(self) -> &'static str {
+        match self {
+            CodecKind::Json => Json::NAME,
+            CodecKind::Bincode => Bincode::NAME,
+            CodecKind::MsgPack => MsgPack::NAME,
+            CodecKind::Cbor => Cbor::NAME,
+        }
+    }
+}
+
+impl FromStr for CodecKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CodecKind::ALL
+            .into_iter()
+            .find(|c| c.name() == s)
+            .ok_or_else(|| format!("unknown codec {}, expected one of json, bincode, msgpack or cbor", s))
+    }
+}
+
+impl fmt::Display for CodecKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A frame was received but couldn't be decoded into the expected message
+/// The framing is intact, so the connection can still be used.
+#[derive(Debug)]
+pub struct DecodeError(String);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unable to decode message: {}", self.0)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<serde_json::Error> for DecodeError {
+    fn from(e: serde_json::Error) -> Self {
+        Self(e.to_string())
+    }
+}
+
+impl From<bincode::Error> for DecodeError {
+    fn from(e: bincode::Error) -> Self {
+        Self(e.to_string())
+    }
+}
+
+impl From<rmp_serde::decode::Error> for DecodeError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        Self(e.to_string())
+    }
+}
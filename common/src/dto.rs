@@ -1,18 +1,277 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
 use serde::{Serialize, Deserialize};
 
+/// Identifies the first frame of the protocol ("RDIC")
+pub const PROTOCOL_MAGIC: u32 = u32::from_be_bytes(*b"RDIC");
+
+/// Protocol version spoken by this build
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this build can talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features supported by this build
+pub const FEATURES: [&str; 2] = ["pipelining", "deadlines"];
+
+/// First frame on a connection, sent by the client
+/// Codecs and features are names, so that peers can list ones the other side doesn't know.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub magic: u32,
+    pub version: u32,
+    pub codecs: Vec<String>,
+    pub features: Vec<String>,
+}
+
+impl Hello {
+    pub fn new(codecs: &[&str]) -> Self {
+        Self {
+            magic: PROTOCOL_MAGIC,
+            version: PROTOCOL_VERSION,
+            codecs: codecs.iter().map(|c| c.to_string()).collect(),
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+}
+
+/// Server answer to a Hello, always the first frame sent by the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum HelloReply {
+    /// The connection can be used with the given version, codec and common features,
+    /// with at most max_pipelined requests in flight at once
+    Accepted { version: u32, codec: String, features: Vec<String>, max_pipelined: u32 },
+    /// The server refuses the connection and closes it
+    Rejected { reason: String },
+}
+
+/// Id of response frames that don't answer a specific request; the server closes the connection after one
+pub const CONNECTION_FRAME_ID: u64 = 0;
+
+/// A request, as sent over the wire
+/// Several requests can be in flight on a connection; responses may come back in any order.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestFrame {
+    /// Client-chosen id, echoed in the response; must not be CONNECTION_FRAME_ID
+    pub id: u64,
+    /// Time budget for the request, in milliseconds from its receipt by the server
+    pub timeout_ms: Option<u64>,
+    pub request: Request,
+}
+
+/// Id of a request frame, decodable even when its request isn't, e.g. one unknown to the server
+/// The id is the first field of the frame, so it is a prefix of the frame with any codec.
 #[derive(Serialize, Deserialize, Debug)]
+pub struct FrameId {
+    pub id: u64,
+}
+
+/// A response, as sent over the wire
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResponseFrame {
+    /// Id of the answered request
+    pub id: u64,
+    pub response: Response,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     Get { key: String },
     Set { key: String, val: String },
     Stats,
+    ResetStats,
+    Delete { key: String },
+    /// Checks that the connection is alive
+    Ping,
+    /// Authenticates the connection; required before other requests if the server has users
+    Auth { user: String, token: Token },
+    /// Queries the audit log of mutating operations; requires admin permission
+    Audit { query: AuditQuery },
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+impl Request {
+    /// Key the request reads or writes, if any
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Request::Get { key } | Request::Set { key, .. } | Request::Delete { key } => Some(key),
+            _ => None,
+        }
+    }
+}
+
+/// Secret authentication token, kept out of debug output so it doesn't end up in logs
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Token(pub String);
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Token(***)")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Response {
-    Get { ok: bool, val: Option<String>, err: Option<String> },
-    Set { ok: bool, err: Option<String> },
-    Stats { ok: bool, total: Option<u64>, good: Option<u64>, bad: Option<u64> },
+    Get { val: String },
+    Set,
+    Stats { stats: StatsReport },
+    ResetStats { reset_at: u64 },
+    /// Whether the deleted key existed
+    Delete { existed: bool },
+    Pong,
+    Auth,
+    /// Matching audit entries, oldest first
+    Audit { entries: Vec<AuditEntry> },
+
+    /// The client exceeded its rate limit and should retry after the given delay
+    RateLimited { retry_after_ms: u64 },
+
+    /// The request failed
+    Error(Error),
+}
+
+/// Filters of an audit log query; unset filters match every entry
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct AuditQuery {
+    pub key: Option<String>,
+    /// Earliest time, inclusive, in UNIX milliseconds
+    pub since_ms: Option<u64>,
+    /// Latest time, exclusive, in UNIX milliseconds
+    pub until_ms: Option<u64>,
+}
+
+/// A mutating operation, as recorded in the audit log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// Time of the operation, in UNIX milliseconds
+    pub timestamp_ms: u64,
+    /// Client identity: user name, certificate common name, otherwise IP address
+    pub client: String,
+    pub address: String,
+    pub op: String,
+    pub key: Option<String>,
+    /// Hex SHA-256 of the written value, so the log doesn't hold the data itself
+    pub value_hash: Option<String>,
+    /// "ok", or why the operation failed
+    pub result: String,
+}
+
+/// Failure reported by the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self { kind, message: message.into() }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}): {}", self.kind, self.kind.code(), self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Category of a failure
+/// Codes follow the HTTP status codes of the same meaning, so they are familiar to scripts.
+/// Kinds added by newer servers are decoded as Unknown.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// The key doesn't exist
+    NotFound,
+    /// The request conflicts with a concurrent one and wasn't applied; it can be retried
+    Conflict,
+    /// The request couldn't be decoded or is malformed
+    InvalidRequest,
+    /// The server failed to process the request
+    Internal,
+    /// The client isn't authenticated
+    Unauthorized,
+    /// The client isn't allowed to make the request
+    Forbidden,
+    /// The request didn't complete within its time budget
+    Timeout,
+    /// A kind unknown to this version; new kinds go before it, so binary codecs decode them as Unknown
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorKind {
+    /// Stable numeric code of the kind
+    pub fn code(&self) -> u16 {
+        match self {
+            ErrorKind::NotFound => 404,
+            ErrorKind::Conflict => 409,
+            ErrorKind::InvalidRequest => 400,
+            ErrorKind::Internal => 500,
+            ErrorKind::Unauthorized => 401,
+            ErrorKind::Forbidden => 403,
+            ErrorKind::Timeout => 504,
+            ErrorKind::Unknown => 500,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorKind::NotFound => "not found",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::InvalidRequest => "invalid request",
+            ErrorKind::Internal => "internal error",
+            ErrorKind::Unauthorized => "unauthorized",
+            ErrorKind::Forbidden => "forbidden",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Unknown => "unknown error",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Server statistics, as returned by a Stats request
+///
+/// Operations and counters are keyed by name, so new ones can be reported
+/// without changing the shape of the response.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct StatsReport {
+    pub uptime_secs: u64,
+    /// Time of the last stats reset, in UNIX seconds
+    pub reset_at: Option<u64>,
+    pub keys: u64,
+    pub storage_bytes: u64,
+    pub cache: CacheStats,
+    pub ops: BTreeMap<String, OpStats>,
+    pub counters: BTreeMap<String, u64>,
+}
+
+/// Counters and latency for a single request kind
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct OpStats {
+    pub total: u64,
+    pub ok: u64,
+    pub not_found: u64,
+    pub err: u64,
+    pub latency: Latency,
+}
+
+/// Latency percentiles, in microseconds
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Latency {
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+}
 
-    #[default]
-    Empty,
+/// Value cache counters
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
 }
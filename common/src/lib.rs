@@ -1,2 +1,4 @@
+pub mod codec;
 pub mod dto;
 pub mod net;
+pub mod tls;
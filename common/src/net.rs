@@ -1,30 +1,140 @@
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
 use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
 use futures::{SinkExt, TryStreamExt};
 use serde::{Serialize, de::DeserializeOwned};
-use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_rustls::TlsStream;
 
-use tokio::net as tn;
-use tokio_serde as ts;
 use tokio_util::codec as tuc;
 
+use crate::codec::{Bincode, Cbor, Codec, CodecKind, Json, MsgPack};
+use crate::tls::{certificate_name, TlsClient};
+
+pub use crate::codec::DecodeError;
+
 /// Helper type alias
-type FramedStream<T, C> = ts::SymmetricallyFramed<tuc::Framed<tn::TcpStream, tuc::LengthDelimitedCodec>, T, C>;
+type FramedStream<S> = tuc::Framed<S, tuc::LengthDelimitedCodec>;
 
 /// Result type used throughout the module
 pub type ConnectionResult<T> = anyhow::Result<T>;
 
-/// Framed stream connection
-pub struct Connection<T, C> {
-    stream: FramedStream<T, C>
+/// Framed stream connection, with messages encoded by the codec C, over any byte stream
+/// The stream defaults to a Transport, i.e. TCP with optional TLS.
+pub struct Connection<C, S = Transport> {
+    stream: FramedStream<S>,
+    codec: PhantomData<C>,
+}
+
+/// Receiving half of a connection, see Connection::split
+pub struct ConnectionReader<C, S = Transport> {
+    stream: tuc::FramedRead<ReadHalf<S>, tuc::LengthDelimitedCodec>,
+    codec: PhantomData<C>,
+}
+
+/// Sending half of a connection, see Connection::split
+pub struct ConnectionWriter<C, S = Transport> {
+    sink: tuc::FramedWrite<WriteHalf<S>, tuc::LengthDelimitedCodec>,
+    codec: PhantomData<C>,
 }
 
 /// Connection with Json codec
-pub type JsonConnection = Connection<Value, tokio_serde::formats::SymmetricalJson<Value>>;
+pub type JsonConnection<S = Transport> = Connection<Json, S>;
 
 /// Connection with Bincode codec
-/// See https://blog.logrocket.com/rust-serialization-whats-ready-for-production-today/
-pub type BincodeConnection = Connection<Vec<u8>, tokio_serde::formats::SymmetricalBincode<Vec<u8>>>;
+pub type BincodeConnection<S = Transport> = Connection<Bincode, S>;
+
+/// Connection with MessagePack codec
+pub type MsgPackConnection<S = Transport> = Connection<MsgPack, S>;
+
+/// Connection with CBOR codec
+pub type CborConnection<S = Transport> = Connection<Cbor, S>;
+
+/// Server address: a TCP socket address, or a Unix socket path written `unix:/path`
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(Address::Unix(PathBuf::from(path))),
+            Some(_) => Err(anyhow::anyhow!("missing Unix socket path in address {}", s)),
+            None => Ok(Address::Tcp(SocketAddr::from_str(s)?)),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(address) => write!(f, "{}", address),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(address: SocketAddr) -> Self {
+        Address::Tcp(address)
+    }
+}
+
+/// Byte stream carrying the frames of a connection
+pub enum Transport {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Transport {
+    /// Opens a plain connection to an address
+    pub async fn connect(address: &Address) -> ConnectionResult<Self> {
+        match address {
+            Address::Tcp(address) => Ok(Transport::Tcp(TcpStream::connect(address).await?)),
+            #[cfg(unix)]
+            Address::Unix(path) => Ok(Transport::Unix(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(anyhow::anyhow!("Unix sockets aren't supported on this platform")),
+        }
+    }
+
+    /// Identity of the peer, from the subject of its TLS certificate
+    pub fn peer_identity(&self) -> Option<String> {
+        let Transport::Tls(stream) = self else { return None };
+
+        let certs = match stream.as_ref() {
+            TlsStream::Client(s) => s.get_ref().1.peer_certificates(),
+            TlsStream::Server(s) => s.get_ref().1.peer_certificates(),
+        };
+
+        certs?.first().and_then(|cert| certificate_name(cert))
+    }
+}
+
+/// Connection accepted with the codec chosen by the client
+pub enum AnyConnection<S = Transport> {
+    Json(JsonConnection<S>),
+    Bincode(BincodeConnection<S>),
+    MsgPack(MsgPackConnection<S>),
+    Cbor(CborConnection<S>),
+}
 
 /// Allows a connection to send requests
 #[async_trait]
@@ -46,125 +156,291 @@ pub trait Listener {
     where
         Res: Send + Serialize;
 
-    /// Awaits requests and returns them
+    /// Awaits requests and returns them, or None once the peer closed the connection
+    /// Frames that can't be decoded fail with a DecodeError.
     async fn listen<Req>(&mut self) -> ConnectionResult<Option<Req>>
     where
         Req: DeserializeOwned;
+
+    /// Awaits requests like listen, decoding the frames that fail as the fallback instead
+    /// The fallback is typically a prefix of the request, such as its id, so that the failure can be answered.
+    /// Frames that can't be decoded as either fail with a DecodeError.
+    async fn listen_with_fallback<Req, F>(&mut self) -> ConnectionResult<Option<Result<Req, (F, DecodeError)>>>
+    where
+        Req: DeserializeOwned,
+        F: DeserializeOwned;
 }
 
-impl<T, C> Connection<T, C>
+impl<C, S> Connection<C, S>
 where
-    C: tokio_serde::Serializer<T> + tokio_serde::Deserializer<T>
+    C: Codec,
+    S: AsyncRead + AsyncWrite
 {
-    /// Creates a connection with a given TCP socket
-    pub fn from_socket(socket: TcpStream) -> Self
-    where C: Default
-    {
-        let length_delimited = tuc::Framed::new(socket, tuc::LengthDelimitedCodec::new());
-        let stream = tokio_serde::SymmetricallyFramed::new(length_delimited, C::default());
+    /// Creates a connection over a given byte stream, e.g. an established TLS session
+    pub fn from_transport(transport: S) -> Self {
+        Self {
+            stream: tuc::Framed::new(transport, tuc::LengthDelimitedCodec::new()),
+            codec: PhantomData,
+        }
+    }
+
+    // Creates a connection with bytes already read from the socket
+    fn from_buffered(transport: S, buffered: &[u8]) -> Self {
+        let mut parts = tuc::FramedParts::new::<bytes::Bytes>(transport, tuc::LengthDelimitedCodec::new());
+        parts.read_buf.extend_from_slice(buffered);
 
         Self {
-            stream,
+            stream: tuc::Framed::from_parts(parts),
+            codec: PhantomData,
         }
     }
 
-    /// Creates a connection with a given IP address
-    pub async fn from_address(address: std::net::SocketAddr) -> ConnectionResult<Self>
-    where C: Default
-    {
-        let socket = TcpStream::connect(address).await?;
-        Ok(Self::from_socket(socket))
+    /// Splits the connection into halves that can be used concurrently,
+    /// e.g. so that reading goes on while a write waits for the peer to read
+    pub fn split(self) -> (ConnectionReader<C, S>, ConnectionWriter<C, S>) {
+        let parts = self.stream.into_parts();
+        let (read, write) = tokio::io::split(parts.io);
+
+        let mut stream = tuc::FramedRead::new(read, parts.codec.clone());
+        stream.read_buffer_mut().extend_from_slice(&parts.read_buf);
+
+        let mut sink = tuc::FramedWrite::new(write, parts.codec);
+        sink.write_buffer_mut().extend_from_slice(&parts.write_buf);
+
+        (ConnectionReader { stream, codec: PhantomData }, ConnectionWriter { sink, codec: PhantomData })
     }
 }
 
-/// Requester implementation for the JSON codec
-#[async_trait]
-impl Requester for JsonConnection {
-    async fn request<Req, Res>(&mut self, req: Req) -> ConnectionResult<Option<Res>>
+impl<C, S> ConnectionReader<C, S>
+where
+    C: Codec,
+    S: AsyncRead + AsyncWrite
+{
+    /// Awaits requests, see Listener::listen
+    pub async fn listen<Req>(&mut self) -> ConnectionResult<Option<Req>>
     where
-        Req: Send + Serialize,
-        Res: DeserializeOwned,
+        Req: DeserializeOwned
     {
-        // write
-        let req = serde_json::to_value(req)?;
-        self.stream.send(req).await?;
-
-        // read
-        let res = self.stream
-            .try_next()
-            .await?
-            .and_then(|r| serde_json::from_value::<Res>(r).ok());
-
-        Ok(res)
+        decode::<C, Req>(self.stream.try_next().await?)
     }
-}
 
-/// Listener implementation for the JSON codec
-#[async_trait]
-impl Listener for JsonConnection {
-    async fn respond<Res>(&mut self, res: Res) -> ConnectionResult<()>
+    /// Awaits requests with a fallback, see Listener::listen_with_fallback
+    pub async fn listen_with_fallback<Req, F>(&mut self) -> ConnectionResult<Option<Result<Req, (F, DecodeError)>>>
     where
-        Res: Send + Serialize
+        Req: DeserializeOwned,
+        F: DeserializeOwned
     {
-        let res = serde_json::to_value(res)?;
-        Ok(self.stream.send(res).await?)
+        decode_with_fallback::<C, Req, F>(self.stream.try_next().await?)
     }
+}
 
-    async fn listen<Req>(&mut self) -> ConnectionResult<Option<Req>>
+impl<C, S> ConnectionWriter<C, S>
+where
+    C: Codec,
+    S: AsyncRead + AsyncWrite
+{
+    /// Sends a message, see Listener::respond
+    pub async fn respond<Res>(&mut self, res: Res) -> ConnectionResult<()>
     where
-        Req: DeserializeOwned
+        Res: Serialize
     {
-        let req = self.stream
-            .try_next()
-            .await?
-            .and_then(|r| serde_json::from_value::<Req>(r).ok());
-        
-        Ok(req)
+        Ok(self.sink.send(Bytes::from(C::encode(&res)?)).await?)
+    }
+}
+
+impl<C: Codec> Connection<C> {
+    /// Codec name, as used in the handshake
+    pub const CODEC: &'static str = C::NAME;
+
+    /// Creates a connection with a given TCP socket
+    pub fn from_socket(socket: TcpStream) -> Self {
+        Self::from_transport(Transport::Tcp(socket))
+    }
+
+    /// Creates a connection with a given IP address or Unix socket path
+    pub async fn from_address(address: impl Into<Address>) -> ConnectionResult<Self> {
+        Ok(Self::from_transport(Transport::connect(&address.into()).await?))
+    }
+
+    /// Creates a TLS connection with a given IP address; TLS isn't used over Unix sockets
+    pub async fn from_address_tls(address: impl Into<Address>, tls: &TlsClient) -> ConnectionResult<Self> {
+        match address.into() {
+            Address::Tcp(address) => {
+                let socket = TcpStream::connect(address).await?;
+                Ok(Self::from_transport(tls.connect(socket).await?))
+            },
+            Address::Unix(_) => Err(anyhow::anyhow!("TLS isn't supported over Unix sockets")),
+        }
+    }
+}
+
+impl<S> AnyConnection<S>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    /// Accepts a connection of any codec, detected from the first frame sent by the client
+    /// The first frame is expected to hold a struct, such as the handshake's Hello:
+    /// bincode frames start with the length of the rest of the frame, JSON ones with '{',
+    /// MessagePack ones with a map or array and CBOR ones with a map.
+    pub async fn accept(mut transport: S) -> ConnectionResult<Self> {
+        // length prefix, then the start of the payload
+        let mut head = [0u8; 4 + 8];
+        transport.read_exact(&mut head[..5]).await?;
+
+        let frame_len = u32::from_be_bytes([head[0], head[1], head[2], head[3]]) as usize;
+        let head = match frame_len {
+            8.. => {
+                transport.read_exact(&mut head[5..]).await?;
+                &head[..]
+            },
+            _ => &head[..5],
+        };
+
+        let connection = match detect(&head[4..], frame_len) {
+            CodecKind::Json => AnyConnection::Json(JsonConnection::from_buffered(transport, head)),
+            CodecKind::Bincode => AnyConnection::Bincode(BincodeConnection::from_buffered(transport, head)),
+            CodecKind::MsgPack => AnyConnection::MsgPack(MsgPackConnection::from_buffered(transport, head)),
+            CodecKind::Cbor => AnyConnection::Cbor(CborConnection::from_buffered(transport, head)),
+        };
+
+        Ok(connection)
+    }
+
+    /// Codec used by the connection
+    pub fn kind(&self) -> CodecKind {
+        match self {
+            AnyConnection::Json(_) => CodecKind::Json,
+            AnyConnection::Bincode(_) => CodecKind::Bincode,
+            AnyConnection::MsgPack(_) => CodecKind::MsgPack,
+            AnyConnection::Cbor(_) => CodecKind::Cbor,
+        }
+    }
+
+    /// Codec name, as used in the handshake
+    pub fn codec(&self) -> &'static str {
+        self.kind().name()
+    }
+}
+
+// Codec of a frame, from the start of its payload and its length
+// Anything unrecognized is left to bincode, whose decoding then fails.
+fn detect(start: &[u8], frame_len: usize) -> CodecKind {
+    if let Ok(len) = <[u8; 8]>::try_from(start) {
+        if u64::from_le_bytes(len) == frame_len as u64 - 8 {
+            return CodecKind::Bincode;
+        }
+    }
+
+    match start[0] {
+        b'{' => CodecKind::Json,
+        // fixmap, fixarray, array 16/32 and map 16/32
+        0x80..=0x9f | 0xdc..=0xdf => CodecKind::MsgPack,
+        // map, of definite or indefinite length
+        0xa0..=0xbb | 0xbf => CodecKind::Cbor,
+        _ => CodecKind::Bincode,
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Transport::Tls(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            Transport::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Transport::Tls(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            Transport::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Transport::Tls(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            Transport::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Transport::Tls(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            Transport::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
     }
 }
 
-/// Requester implementation for the Bincode codec
 #[async_trait]
-impl Requester for BincodeConnection {
+impl<C, S> Requester for Connection<C, S>
+where
+    C: Codec,
+    S: AsyncRead + AsyncWrite + Unpin + Send
+{
     async fn request<Req, Res>(&mut self, req: Req) -> ConnectionResult<Option<Res>>
     where
         Req: Send + Serialize,
         Res: DeserializeOwned,
     {
-        // read
-        let req = bincode::serialize(&req)?;
-        self.stream.send(req).await?;
-
-        // write
-        let res = self.stream
-            .try_next()
-            .await?
-            .and_then(|r| bincode::deserialize(&r).ok());
-
-        Ok(res)
+        self.respond(req).await?;
+        self.listen().await
     }
 }
 
-/// Listener implementation for the Bincode codec
 #[async_trait]
-impl Listener for BincodeConnection {
+impl<C, S> Listener for Connection<C, S>
+where
+    C: Codec,
+    S: AsyncRead + AsyncWrite + Unpin + Send
+{
     async fn respond<Res>(&mut self, res: Res) -> ConnectionResult<()>
     where
         Res: Send + Serialize
     {
-        let res = bincode::serialize(&res)?;
-        Ok(self.stream.send(res).await?)
+        Ok(self.stream.send(Bytes::from(C::encode(&res)?)).await?)
     }
 
     async fn listen<Req>(&mut self) -> ConnectionResult<Option<Req>>
     where
         Req: DeserializeOwned
     {
-        let req = self.stream
-            .try_next()
-            .await?
-            .and_then(|r| bincode::deserialize(&r).ok());
-        
-        Ok(req)
+        decode::<C, Req>(self.stream.try_next().await?)
+    }
+
+    async fn listen_with_fallback<Req, F>(&mut self) -> ConnectionResult<Option<Result<Req, (F, DecodeError)>>>
+    where
+        Req: DeserializeOwned,
+        F: DeserializeOwned
+    {
+        decode_with_fallback::<C, Req, F>(self.stream.try_next().await?)
+    }
+}
+
+// Decodes a received frame, if any
+fn decode<C: Codec, Req: DeserializeOwned>(frame: Option<BytesMut>) -> ConnectionResult<Option<Req>> {
+    Ok(frame.map(|frame| C::decode::<Req>(&frame)).transpose()?)
+}
+
+// Decodes a received frame, if any, as the fallback if it isn't a request
+fn decode_with_fallback<C, Req, F>(frame: Option<BytesMut>) -> ConnectionResult<Option<Result<Req, (F, DecodeError)>>>
+where
+    C: Codec,
+    Req: DeserializeOwned,
+    F: DeserializeOwned,
+{
+    let Some(frame) = frame else { return Ok(None) };
+
+    match C::decode::<Req>(&frame) {
+        Ok(req) => Ok(Some(Ok(req))),
+        Err(e) => Ok(Some(Err((C::decode::<F>(&frame)?, e)))),
     }
 }
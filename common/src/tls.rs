@@ -0,0 +1,132 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::net::{ConnectionResult, Transport};
+
+/// Server side of TLS connections
+#[derive(Clone)]
+pub struct TlsServer {
+    acceptor: TlsAcceptor,
+}
+
+impl TlsServer {
+    /// Loads the server's PEM certificate chain and private key
+    /// With a client CA, clients must present a certificate signed by it.
+    pub fn new(cert_path: &Path, key_path: &Path, client_ca_path: Option<&Path>) -> anyhow::Result<Self> {
+        let builder = ServerConfig::builder();
+
+        let builder = match client_ca_path {
+            Some(path) => builder.with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(load_roots(path)?)).build()?),
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = builder.with_single_cert(load_certs(cert_path)?, load_key(key_path)?)?;
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    /// Runs the TLS handshake on an accepted socket
+    pub async fn accept(&self, socket: TcpStream) -> ConnectionResult<Transport> {
+        let stream = self.acceptor.accept(socket).await?;
+        Ok(Transport::Tls(Box::new(stream.into())))
+    }
+}
+
+/// Client side of TLS connections
+#[derive(Clone)]
+pub struct TlsClient {
+    roots: Arc<RootCertStore>,
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl TlsClient {
+    /// Trusts the CA certificates of a PEM file, for servers with the given name
+    pub fn new(ca_path: &Path, server_name: &str) -> anyhow::Result<Self> {
+        let roots = Arc::new(load_roots(ca_path)?);
+
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth();
+
+        Ok(Self {
+            roots,
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: ServerName::try_from(server_name.to_owned())?,
+        })
+    }
+
+    /// Presents a PEM certificate chain and private key, for servers requiring client certificates
+    pub fn with_client_cert(self, cert_path: &Path, key_path: &Path) -> anyhow::Result<Self> {
+        let config = ClientConfig::builder()
+            .with_root_certificates(self.roots.clone())
+            .with_client_auth_cert(load_certs(cert_path)?, load_key(key_path)?)?;
+
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            ..self
+        })
+    }
+
+    /// Runs the TLS handshake on a connected socket
+    pub async fn connect(&self, socket: TcpStream) -> ConnectionResult<Transport> {
+        let stream = self.connector.connect(self.server_name.clone(), socket).await?;
+        Ok(Transport::Tls(Box::new(stream.into())))
+    }
+}
+
+/// Common name of a DER certificate's subject, used as the identity of its owner
+pub fn certificate_name(der: &[u8]) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+
+    let name = cert.subject()
+        .iter_common_name()
+        .next()?
+        .as_str()
+        .ok()?
+        .to_owned();
+
+    Some(name)
+}
+
+// Reads the CA certificates of a PEM file
+fn load_roots(path: &Path) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+
+    Ok(roots)
+}
+
+// Reads all certificates of a PEM file
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("unable to open {:?}", path))?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate in {:?}", path));
+    }
+
+    Ok(certs)
+}
+
+// Reads the first private key of a PEM file
+fn load_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("unable to open {:?}", path))?;
+
+    rustls_pemfile::private_key(&mut BufReader::new(file))?
+        .ok_or_else(|| anyhow!("no private key in {:?}", path))
+}
@@ -0,0 +1,211 @@
+use std::path::PathBuf;
+use std::{net::SocketAddr, str::FromStr};
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use serde::{Serialize, Deserialize};
+use tokio::net::TcpListener;
+
+use common::net::{BincodeConnection, Listener, Requester};
+use common::tls::{TlsClient, TlsServer};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct TestThing {
+    pub test_bool: bool,
+    pub test_string: String,
+}
+
+// Test PKI written to a temporary directory: a CA, and certificates signed by it
+// for the server on localhost and for a client named alice
+struct TestCerts {
+    dir: PathBuf,
+}
+
+impl TestCerts {
+    fn generate(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("dict-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).expect("failed temp dir");
+
+        let ca_key = KeyPair::generate().expect("failed ca key");
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).expect("failed ca params");
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "Test CA");
+        let ca = ca_params.self_signed(&ca_key).expect("failed ca");
+
+        let key = KeyPair::generate().expect("failed server key");
+        let params = CertificateParams::new(vec!["localhost".to_owned()]).expect("failed server params");
+        let cert = params.signed_by(&key, &ca, &ca_key).expect("failed server cert");
+
+        let client_key = KeyPair::generate().expect("failed client key");
+        let mut client_params = CertificateParams::new(Vec::<String>::new()).expect("failed client params");
+        client_params.distinguished_name.push(DnType::CommonName, "alice");
+        let client_cert = client_params.signed_by(&client_key, &ca, &ca_key).expect("failed client cert");
+
+        std::fs::write(dir.join("ca.pem"), ca.pem()).expect("failed write");
+        std::fs::write(dir.join("server.pem"), cert.pem()).expect("failed write");
+        std::fs::write(dir.join("server.key"), key.serialize_pem()).expect("failed write");
+        std::fs::write(dir.join("client.pem"), client_cert.pem()).expect("failed write");
+        std::fs::write(dir.join("client.key"), client_key.serialize_pem()).expect("failed write");
+
+        Self { dir }
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+}
+
+impl Drop for TestCerts {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[tokio::test]
+async fn test_tls_write_read() {
+    let address = "127.0.0.1:8138";
+    let certs = TestCerts::generate("round-trip");
+
+    let response = TestThing { test_bool: false, test_string: "test_res".to_owned() };
+    let request = TestThing { test_bool: true, test_string: "test_req".to_owned() };
+
+    let tls = TlsServer::new(&certs.path("server.pem"), &certs.path("server.key"), None)
+        .expect("failed server tls");
+
+    let listener = TcpListener::bind(address)
+        .await
+        .expect("failed bind");
+
+    let res = response.clone();
+    let req = request.clone();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept()
+            .await
+            .expect("failed accept");
+
+        let transport = tls.accept(socket)
+            .await
+            .expect("failed tls handshake");
+
+        let mut s = BincodeConnection::from_transport(transport);
+
+        let r = s.listen::<TestThing>()
+            .await
+            .expect("no request")
+            .expect("empty request");
+
+        assert_eq!(req, r, "bad request");
+
+        s.respond(res)
+            .await
+            .expect("failed response")
+    });
+
+    let tls = TlsClient::new(&certs.path("ca.pem"), "localhost")
+        .expect("failed client tls");
+
+    let mut c = BincodeConnection::from_address_tls(SocketAddr::from_str(address).unwrap(), &tls)
+        .await
+        .expect("socket failure");
+
+    let r = c.request::<TestThing, TestThing>(request)
+        .await
+        .expect("failed request")
+        .expect("empty response");
+
+    assert_eq!(r, response, "bad response");
+}
+
+#[tokio::test]
+async fn test_tls_untrusted_server() {
+    let address = "127.0.0.1:8139";
+    let certs = TestCerts::generate("untrusted");
+    let other = TestCerts::generate("other");
+
+    let tls = TlsServer::new(&certs.path("server.pem"), &certs.path("server.key"), None)
+        .expect("failed server tls");
+
+    let listener = TcpListener::bind(address)
+        .await
+        .expect("failed bind");
+
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept()
+            .await
+            .expect("failed accept");
+
+        _ = tls.accept(socket).await;
+    });
+
+    // the client trusts another CA
+    let tls = TlsClient::new(&other.path("ca.pem"), "localhost")
+        .expect("failed client tls");
+
+    let connected = BincodeConnection::from_address_tls(SocketAddr::from_str(address).unwrap(), &tls).await;
+    assert!(connected.is_err(), "untrusted server accepted");
+}
+
+#[tokio::test]
+async fn test_mutual_tls() {
+    let address = "127.0.0.1:8140";
+    let certs = TestCerts::generate("mutual");
+
+    let tls = TlsServer::new(&certs.path("server.pem"), &certs.path("server.key"), Some(&certs.path("ca.pem")))
+        .expect("failed server tls");
+
+    let listener = TcpListener::bind(address)
+        .await
+        .expect("failed bind");
+
+    // echoes the client identity to the client with a certificate, then refuses the one without
+    let server = tokio::spawn(async move {
+        let (socket, _) = listener.accept()
+            .await
+            .expect("failed accept");
+
+        let transport = tls.accept(socket)
+            .await
+            .expect("failed tls handshake");
+
+        let identity = transport.peer_identity();
+        let mut s = BincodeConnection::from_transport(transport);
+
+        let mut r = s.listen::<TestThing>()
+            .await
+            .expect("no request")
+            .expect("empty request");
+
+        r.test_string = identity.unwrap_or_default();
+        s.respond(r)
+            .await
+            .expect("failed response");
+
+        let (socket, _) = listener.accept()
+            .await
+            .expect("failed accept");
+
+        assert!(tls.accept(socket).await.is_err(), "client without certificate accepted");
+    });
+
+    let tls = TlsClient::new(&certs.path("ca.pem"), "localhost")
+        .expect("failed client tls");
+
+    let with_cert = tls.clone()
+        .with_client_cert(&certs.path("client.pem"), &certs.path("client.key"))
+        .expect("failed client cert");
+
+    let mut c = BincodeConnection::from_address_tls(SocketAddr::from_str(address).unwrap(), &with_cert)
+        .await
+        .expect("socket failure");
+
+    let request = TestThing { test_bool: true, test_string: "test_req".to_owned() };
+    let r = c.request::<TestThing, TestThing>(request)
+        .await
+        .expect("failed request")
+        .expect("empty response");
+
+    assert_eq!(r.test_string, "alice", "bad identity");
+
+    // the TLS 1.3 handshake completes on the client before the server checks its certificate
+    _ = BincodeConnection::from_address_tls(SocketAddr::from_str(address).unwrap(), &tls).await;
+
+    server.await.expect("failed server");
+}
@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use common::dto::{Error, ErrorKind, Request};
+
+use crate::config::{AclConfig, Permission};
+use crate::stats::Op;
+
+// Enforces the access control lists on requests
+pub struct AccessControl {
+    config: AclConfig,
+    denied: AtomicU64,
+}
+
+impl AccessControl {
+    pub fn new(config: AclConfig) -> Self {
+        Self {
+            config,
+            denied: AtomicU64::new(0),
+        }
+    }
+
+    /// Checks that a client may make a request, or returns why it may not
+    pub fn check(&self, client: &str, req: &Request) -> Result<(), Error> {
+        let op = Op::of(req);
+
+        let Some(permission) = Permission::of(op) else {
+            return Ok(());
+        };
+
+        if self.config.is_empty() {
+            return Ok(());
+        }
+
+        let denial = match self.config.acl(client) {
+            None => Some(format!("no access for {}", client)),
+            Some(acl) if !acl.allows(permission) => Some(format!("{} requires {} permission", op.name(), permission.name())),
+            Some(acl) => req.key()
+                .filter(|key| !acl.allows_key(key))
+                .map(|key| format!("key {} not allowed", key)),
+        };
+
+        match denial {
+            Some(message) => {
+                self.denied.fetch_add(1, Ordering::Relaxed);
+                Err(Error::new(ErrorKind::Forbidden, message))
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Access control metrics, as named counters
+    pub fn metrics(&self) -> [(String, u64); 1] {
+        [("acl.denied".to_owned(), self.denied.load(Ordering::Relaxed))]
+    }
+}
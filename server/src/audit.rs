@@ -0,0 +1,204 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use common::dto::{AuditEntry, AuditQuery, Request, Response};
+use common::net::Address;
+
+use crate::stats::Op;
+
+/// Most entries returned by a query; the latest matching ones are kept
+pub const MAX_QUERY_ENTRIES: usize = 1000;
+
+// Append-only log of the mutating operations, one JSON entry per line
+// Once the file grows past the size limit it is rotated: audit.log becomes audit.log.1,
+// audit.log.1 becomes audit.log.2, and so on; files past the file limit are dropped.
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    current: Mutex<Current>,
+    recorded: AtomicU64,
+    failed: AtomicU64,
+}
+
+// File being appended to, and its size
+struct Current {
+    file: File,
+    size: u64,
+}
+
+impl AuditLog {
+    /// Opens the log for appending, creating it if needed
+    /// `max_files` is the number of rotated files kept besides the current one.
+    pub fn open(path: &Path, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let file = append(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_owned(),
+            max_bytes,
+            max_files,
+            current: Mutex::new(Current { file, size }),
+            recorded: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        })
+    }
+
+    /// Appends an entry, rotating the file first if it would grow past the size limit
+    pub fn record(&self, entry: &AuditEntry) -> io::Result<()> {
+        let result = self.append(entry);
+
+        let counter = if result.is_ok() { &self.recorded } else { &self.failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        result
+    }
+
+    fn append(&self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+
+        if current.size > 0 && current.size + line.len() as u64 > self.max_bytes {
+            self.rotate(&mut current)?;
+        }
+
+        current.file.write_all(&line)?;
+        current.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&self, current: &mut Current) -> io::Result<()> {
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            // renaming over the oldest file drops it
+            for i in (1..self.max_files).rev() {
+                match std::fs::rename(self.rotated(i), self.rotated(i + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {},
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        *current = Current { file: append(&self.path)?, size: 0 };
+
+        Ok(())
+    }
+
+    fn rotated(&self, i: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", i));
+        path.into()
+    }
+
+    /// Returns the entries matching a query, oldest first, at most MAX_QUERY_ENTRIES
+    pub fn query(&self, query: &AuditQuery) -> io::Result<Vec<AuditEntry>> {
+        let mut entries = VecDeque::new();
+
+        for (file, size) in self.snapshot()? {
+            for line in BufReader::new(file.take(size)).lines() {
+                // a torn last line, e.g. after a crash, is skipped
+                let Ok(entry) = serde_json::from_str::<AuditEntry>(&line?) else { continue };
+
+                if matches(query, &entry) {
+                    if entries.len() == MAX_QUERY_ENTRIES {
+                        entries.pop_front();
+                    }
+                    entries.push_back(entry);
+                }
+            }
+        }
+
+        Ok(entries.into())
+    }
+
+    // Opens the files to query, oldest first, with the size to read each up to
+    // Only the opening holds the lock: open files are unaffected by later rotations,
+    // and the current file is read up to its size at the time, so writes aren't held up by queries.
+    fn snapshot(&self) -> io::Result<Vec<(File, u64)>> {
+        let current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+
+        let files = (1..=self.max_files).rev()
+            .map(|i| (self.rotated(i), u64::MAX))
+            .chain([(self.path.clone(), current.size)]);
+
+        let mut snapshot = Vec::new();
+
+        for (path, size) in files {
+            match File::open(&path) {
+                Ok(file) => snapshot.push((file, size)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Audit log metrics, as named counters
+    pub fn metrics(&self) -> [(String, u64); 2] {
+        [
+            ("audit.recorded".to_owned(), self.recorded.load(Ordering::Relaxed)),
+            ("audit.failed".to_owned(), self.failed.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+/// Starts the audit entry of a request, if its operation is audited
+/// The result is filled in once the request is answered, see `result`.
+pub fn entry(req: &Request, client: &str, address: &Address) -> Option<AuditEntry> {
+    let op = Op::of(req);
+    if !op.is_write() && op != Op::ResetStats {
+        return None;
+    }
+
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+
+    let value_hash = match req {
+        Request::Set { val, .. } => Some(hex::encode(Sha256::digest(val.as_bytes()))),
+        _ => None,
+    };
+
+    Some(AuditEntry {
+        timestamp_ms,
+        client: client.to_owned(),
+        address: address.to_string(),
+        op: op.name().to_owned(),
+        key: req.key().map(str::to_owned),
+        value_hash,
+        result: String::new(),
+    })
+}
+
+/// Result of a request, as recorded in the audit log
+pub fn result(response: &Response) -> String {
+    match response {
+        Response::Error(e) => e.to_string(),
+        Response::RateLimited { .. } => "rate limited".to_owned(),
+        Response::Delete { existed: false } => "not found".to_owned(),
+        _ => "ok".to_owned(),
+    }
+}
+
+fn matches(query: &AuditQuery, entry: &AuditEntry) -> bool {
+    query.key.as_ref().is_none_or(|key| entry.key.as_ref() == Some(key))
+        && query.since_ms.is_none_or(|since| entry.timestamp_ms >= since)
+        && query.until_ms.is_none_or(|until| entry.timestamp_ms < until)
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
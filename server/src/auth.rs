@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// Credentials of a user, as stored in the config
+///
+/// The hash is the hex SHA-256 of the salt followed by the token,
+/// see `UserConfig::new` to make one.
+///
+/// ```toml
+/// [users.alice]
+/// salt = "5f2b8e0c1d9a7f3e"
+/// hash = "..."
+/// ```
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub salt: String,
+    pub hash: String,
+}
+
+impl UserConfig {
+    /// Hashes a token with a new random salt
+    pub fn new(token: &str) -> Self {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let salt = hex::encode(salt);
+
+        Self {
+            hash: hash(&salt, token),
+            salt,
+        }
+    }
+
+    fn verify(&self, token: &str) -> bool {
+        constant_time_eq(hash(&self.salt, token).as_bytes(), self.hash.to_ascii_lowercase().as_bytes())
+    }
+}
+
+// Checks user tokens against the configured users
+// Authentication is required once at least one user is configured.
+pub struct Authenticator {
+    users: HashMap<String, UserConfig>,
+    succeeded: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl Authenticator {
+    pub fn new(users: HashMap<String, UserConfig>) -> Self {
+        Self {
+            users,
+            succeeded: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether connections must authenticate before making requests
+    pub fn is_required(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    /// Checks a user's token
+    pub fn verify(&self, user: &str, token: &str) -> bool {
+        let verified = self.users
+            .get(user)
+            .is_some_and(|u| u.verify(token));
+
+        let counter = if verified { &self.succeeded } else { &self.failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        verified
+    }
+
+    /// Authentication metrics, as named counters
+    pub fn metrics(&self) -> [(String, u64); 2] {
+        [
+            ("auth.succeeded".to_owned(), self.succeeded.load(Ordering::Relaxed)),
+            ("auth.failed".to_owned(), self.failed.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+fn hash(salt: &str, token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(token.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+// Compares without exiting early, so the time taken doesn't reveal how much matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::auth::UserConfig;
+use crate::stats::Op;
+
+/// Server settings loaded from the TOML file given with --config
+/// Every section is optional.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub rate_limit: RateLimitConfig,
+    /// Users allowed to authenticate, by name; connections must authenticate if any
+    pub users: HashMap<String, UserConfig>,
+    pub acl: AclConfig,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read config file {:?}", path))?;
+
+        let config: Config = toml::from_str(&text)
+            .with_context(|| format!("Unable to parse config file {:?}", path))?;
+
+        config.validate()
+            .with_context(|| format!("Invalid config file {:?}", path))?;
+
+        Ok(config)
+    }
+
+    /// Checks the settings that parse but can't be applied
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.rate_limit.validate()
+    }
+}
+
+/// Token bucket limits, per client and per operation
+///
+/// The most specific limit applies: the client's limit for the operation,
+/// then the client's default, then the operation's limit, then the default.
+/// Requests without any matching limit are not limited.
+///
+/// ```toml
+/// [rate_limit]
+/// default = { rate = 100.0, burst = 200 }
+/// ops.set = { rate = 10.0, burst = 20 }
+///
+/// [rate_limit.clients."10.0.0.5"]
+/// default = { rate = 1000.0, burst = 1000 }
+/// ```
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub default: Option<Limit>,
+    pub ops: HashMap<Op, Limit>,
+    /// Keyed by client identity: user name, certificate common name, otherwise IP address
+    pub clients: HashMap<String, ClientLimits>,
+}
+
+impl RateLimitConfig {
+    /// Returns the limit for a client and operation, if any
+    pub fn limit(&self, client: &str, op: Op) -> Option<Limit> {
+        let client = self.clients.get(client);
+
+        client.and_then(|c| c.ops.get(&op).copied())
+            .or_else(|| client.and_then(|c| c.default))
+            .or_else(|| self.ops.get(&op).copied())
+            .or(self.default)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.ops.is_empty() && self.clients.is_empty()
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let client_limits = self.clients
+            .iter()
+            .flat_map(|(client, limits)| limits.default.iter().chain(limits.ops.values()).map(move |l| (client.as_str(), l)));
+
+        let limits = self.default.iter()
+            .chain(self.ops.values())
+            .map(|l| ("", l))
+            .chain(client_limits);
+
+        for (client, limit) in limits {
+            if let Err(e) = limit.validate() {
+                match client {
+                    "" => bail!("{}", e),
+                    client => bail!("{} for client {}", e, client),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientLimits {
+    pub default: Option<Limit>,
+    pub ops: HashMap<Op, Limit>,
+}
+
+/// Sustained rate, in requests per second, and the burst allowed on top of it
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+impl Limit {
+    // the rate must allow some requests, and the burst at least one at a time
+    fn validate(&self) -> anyhow::Result<()> {
+        if !self.rate.is_finite() || self.rate <= 0.0 {
+            bail!("invalid rate limit rate {}, expected a positive number of requests per second", self.rate);
+        }
+        if self.burst < 1 {
+            bail!("invalid rate limit burst {}, expected at least 1", self.burst);
+        }
+
+        Ok(())
+    }
+}
+
+/// Access control lists, per client
+///
+/// Clients get their own ACL, otherwise the default one; requests without any
+/// matching ACL are denied. Without any ACL, every request is allowed.
+///
+/// ```toml
+/// [acl]
+/// default = { permissions = ["read"], prefixes = ["public/"] }
+///
+/// [acl.clients.alice]
+/// permissions = ["read", "write"]
+/// prefixes = ["alice/", "public/"]
+/// ```
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    pub default: Option<Acl>,
+    /// Keyed by client identity: user name, certificate common name, otherwise IP address
+    pub clients: HashMap<String, Acl>,
+}
+
+impl AclConfig {
+    /// Returns the ACL of a client, if any
+    pub fn acl(&self, client: &str) -> Option<&Acl> {
+        self.clients.get(client).or(self.default.as_ref())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.clients.is_empty()
+    }
+}
+
+/// Operations a client may make, and the keys it may touch
+/// Without prefixes, every key is allowed.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Acl {
+    pub permissions: Vec<Permission>,
+    pub prefixes: Vec<String>,
+}
+
+impl Acl {
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    pub fn allows_key(&self, key: &str) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| key.starts_with(prefix.as_str()))
+    }
+}
+
+/// Kind of access: read and write cover the keys, admin covers the stats and the audit log
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Permission {
+    /// Permission needed for an operation, if any
+    pub fn of(op: Op) -> Option<Self> {
+        match op {
+            Op::Get => Some(Permission::Read),
+            Op::Set | Op::Delete => Some(Permission::Write),
+            Op::Stats | Op::ResetStats | Op::Audit => Some(Permission::Admin),
+            Op::Ping | Op::Auth => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Admin => "admin",
+        }
+    }
+}
@@ -0,0 +1,486 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use common::codec::{Codec, CodecKind};
+use common::dto::{AuditEntry, Error, ErrorKind, FrameId, Hello, HelloReply, Request, RequestFrame, Response, ResponseFrame, CONNECTION_FRAME_ID};
+use common::net::{Address, AnyConnection, Connection, ConnectionWriter, DecodeError, Listener, Transport};
+use common::tls::TlsServer;
+
+use crate::{is_conflict, Db};
+use crate::acl::AccessControl;
+use crate::audit::{self, AuditLog};
+use crate::auth::Authenticator;
+use crate::handshake::{negotiate, rejected};
+use crate::limits::{ConnectionGuard, ConnectionLimiter, Rejection};
+use crate::pipeline::StatsProducer;
+use crate::ratelimit::RateLimiter;
+use crate::stats::{Op, Outcome, StatEvent, Stats};
+
+/// Failed authentications after which a connection is closed
+pub const MAX_AUTH_FAILURES: u32 = 3;
+
+/// Maximum time to wait for the client's Hello, including the TLS handshake and codec detection
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum time spent telling a rejected connection why, including the TLS handshake and codec detection
+pub const REJECTION_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// State shared by all connections
+pub struct Context {
+    pub dict: Arc<Db<String, String>>,
+    pub stats: Arc<Stats>,
+    pub stats_producer: StatsProducer,
+    pub limiter: Arc<ConnectionLimiter>,
+    pub rate_limiter: RateLimiter,
+    pub auth: Authenticator,
+    pub acl: AccessControl,
+    pub audit: Arc<AuditLog>,
+    /// Time after which a connection without requests in flight is closed
+    pub idle_timeout: Option<Duration>,
+    /// Maximum time to process a request, whatever the client's own budget
+    pub request_timeout: Option<Duration>,
+    /// Requests read from a connection before waiting for their responses
+    pub max_pipelined: usize,
+    /// Codecs clients may use
+    pub codecs: Vec<CodecKind>,
+    pub tls: Option<TlsServer>,
+}
+
+/// IP a client is identified by, without a certificate or user
+/// Unix socket clients are on the same host, so they count as localhost.
+pub fn peer_ip(address: &Address) -> IpAddr {
+    match address {
+        Address::Tcp(address) => address.ip(),
+        Address::Unix(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+    }
+}
+
+/// Registers a new connection with the connection limits
+/// Unix socket clients are local and have no address, so only the total limit applies to them.
+pub fn acquire_connection(limiter: &Arc<ConnectionLimiter>, address: &Address) -> Result<ConnectionGuard, Rejection> {
+    match address {
+        Address::Tcp(address) => limiter.try_acquire(address.ip()),
+        Address::Unix(_) => limiter.try_acquire_local(),
+    }
+}
+
+/// Establishes TLS if enabled and detects the codec chosen by the client, then serves the connection with it
+/// TLS only applies to TCP: Unix socket connections don't leave the host.
+/// Excess connections get a rejected handshake within REJECTION_TIMEOUT and are closed, or are closed
+/// right away when MAX_REJECTING others are already being rejected.
+pub async fn accept(transport: Transport, address: Address, admission: Result<ConnectionGuard, Rejection>, ctx: Arc<Context>) {
+    let (timeout, _rejecting) = match &admission {
+        Ok(_) => (HANDSHAKE_TIMEOUT, None),
+        Err(_) => match ctx.limiter.try_reject() {
+            Some(rejecting) => (REJECTION_TIMEOUT, Some(rejecting)),
+            None => return,
+        },
+    };
+
+    let detected = tokio::time::timeout(timeout, async {
+        let transport = match (&ctx.tls, transport) {
+            (Some(tls), Transport::Tcp(socket)) => tls.accept(socket).await?,
+            (_, transport) => transport,
+        };
+        let identity = transport.peer_identity();
+        Ok::<_, anyhow::Error>((AnyConnection::accept(transport).await?, identity))
+    }).await;
+
+    let (connection, identity) = match detected {
+        Ok(Ok(detected)) => detected,
+        failed => {
+            match failed {
+                Ok(Err(e)) => eprintln!("Connection setup failed for client {}. Error {:?}", address, e),
+                _ => println!("Connection setup timed out for client {}", address),
+            }
+            // rejected connections are already counted
+            if admission.is_ok() {
+                ctx.limiter.handshake_failed();
+            }
+            return;
+        },
+    };
+
+    if let Some(identity) = &identity {
+        println!("Client with address {} identified as {}", address, identity);
+    }
+
+    let codec = connection.codec();
+    match connection {
+        AnyConnection::Json(connection) => serve(connection, codec, address, identity, admission, ctx).await,
+        AnyConnection::Bincode(connection) => serve(connection, codec, address, identity, admission, ctx).await,
+        AnyConnection::MsgPack(connection) => serve(connection, codec, address, identity, admission, ctx).await,
+        AnyConnection::Cbor(connection) => serve(connection, codec, address, identity, admission, ctx).await,
+    }
+}
+
+/// Handles the requests of a client until it disconnects or goes idle
+/// Requests are processed concurrently and answered as they complete, possibly out of order.
+/// Responses are written by their own task, so that requests are still read while the client
+/// is slow to read the responses.
+/// Clients are known by their certificate identity if they have one, otherwise by their IP.
+pub async fn serve<C, S>(mut connection: Connection<C, S>, codec: &str, address: Address, identity: Option<String>, admission: Result<ConnectionGuard, Rejection>, ctx: Arc<Context>)
+where
+    C: Codec,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // the connection slot is held until the client is gone
+    let _guard = match admission {
+        Ok(guard) => guard,
+        Err(rejection) => {
+            match tokio::time::timeout(REJECTION_TIMEOUT, connection.respond(rejected(rejection.to_string()))).await {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => eprintln!("Rejection failed. Error {:?}", e),
+                Err(_) => println!("Rejection timed out for client {}", address),
+            }
+            return;
+        },
+    };
+
+    if !handshake(&mut connection, codec, &address, &ctx).await {
+        ctx.limiter.handshake_failed();
+        return;
+    }
+
+    // clients with a certificate are already authenticated
+    let mut authenticated = identity.is_some() || !ctx.auth.is_required();
+    let mut auth_failures = 0;
+    let mut client = Arc::new(identity.unwrap_or_else(|| peer_ip(&address).to_string()));
+
+    let (mut reader, writer) = connection.split();
+
+    // every request gets exactly one response, and requests stay in flight until it is written,
+    // so neither channel ever fills up
+    let (responses, outgoing) = channel::<ResponseFrame>(ctx.max_pipelined);
+    let (written, mut acknowledged) = channel::<()>(ctx.max_pipelined);
+    let writer = tokio::spawn(write_responses(writer, outgoing, written, address.clone(), ctx.clone()));
+    let mut in_flight = 0;
+
+    let idle = tokio::time::sleep(ctx.idle_timeout.unwrap_or(Duration::MAX));
+    tokio::pin!(idle);
+
+    loop {
+        tokio::select! {
+            listened = reader.listen_with_fallback::<RequestFrame, FrameId>(), if in_flight < ctx.max_pipelined => {
+                in_flight += 1;
+
+                match listened {
+                    Ok(Some(Ok(RequestFrame { id, request: Request::Auth { user, token }, .. }))) => {
+                        let response = authenticate(&user, &token.0, &client, &ctx).await;
+
+                        match response {
+                            Response::Auth => {
+                                println!("Client with address {} authenticated as {}", address, user);
+                                authenticated = true;
+                                client = Arc::new(user);
+                            },
+                            Response::Error(_) => auth_failures += 1,
+                            _ => (),
+                        }
+
+                        _ = responses.send(ResponseFrame { id, response }).await;
+
+                        if auth_failures >= MAX_AUTH_FAILURES {
+                            println!("Closing client with address {} after {} failed authentications", address, auth_failures);
+                            break;
+                        }
+                    },
+                    Ok(Some(Ok(frame))) if !authenticated && !matches!(frame.request, Request::Ping) => {
+                        let response = Response::Error(Error::new(ErrorKind::Unauthorized, "authenticate first"));
+                        _ = responses.send(ResponseFrame { id: frame.id, response }).await;
+                    },
+                    Ok(Some(Ok(frame))) => {
+                        println!("Processing request {:?}", frame);
+
+                        let (client, address, ctx, responses) = (client.clone(), address.clone(), ctx.clone(), responses.clone());
+                        tokio::spawn(async move {
+                            let response = handle(frame.request, frame.timeout_ms, &client, &address, &ctx).await;
+                            _ = responses.send(ResponseFrame { id: frame.id, response }).await;
+                        });
+                    },
+                    // e.g. a request unknown to this server, answered with the id of its frame
+                    Ok(Some(Err((FrameId { id }, e)))) => {
+                        println!("Invalid request from client {}: {}", address, e);
+                        let response = Response::Error(Error::new(ErrorKind::InvalidRequest, e.to_string()));
+                        _ = responses.send(ResponseFrame { id, response }).await;
+                    },
+                    // without an id the error can only be reported on the connection, which is then closed
+                    Err(e) if e.is::<DecodeError>() => {
+                        println!("Invalid frame from client {}: {}", address, e);
+                        let response = Response::Error(Error::new(ErrorKind::InvalidRequest, e.to_string()));
+                        _ = responses.send(ResponseFrame { id: CONNECTION_FRAME_ID, response }).await;
+                        break;
+                    },
+                    _ => break,
+                }
+            },
+            acknowledged = acknowledged.recv() => {
+                // the writer stops once the connection fails
+                let Some(()) = acknowledged else { break };
+                in_flight -= 1;
+            },
+            _ = &mut idle, if ctx.idle_timeout.is_some() && in_flight == 0 => {
+                println!("Closing idle client with address {}", address);
+                ctx.limiter.idle_closed();
+                break;
+            },
+        }
+
+        if let Some(idle_timeout) = ctx.idle_timeout {
+            idle.as_mut().reset((Instant::now() + idle_timeout).into());
+        }
+    }
+
+    // the responses of the requests already read are written before the connection is closed
+    drop(responses);
+    _ = writer.await;
+}
+
+// Writes the responses of a connection as they are ready, acknowledging each one written
+// Clients that don't read their responses within the idle timeout are given up on.
+async fn write_responses<C, S>(mut writer: ConnectionWriter<C, S>, mut outgoing: Receiver<ResponseFrame>, written: Sender<()>, address: Address, ctx: Arc<Context>)
+where
+    C: Codec,
+    S: AsyncRead + AsyncWrite,
+{
+    while let Some(frame) = outgoing.recv().await {
+        println!("Responding back");
+
+        let write = writer.respond(frame);
+        let result = match ctx.idle_timeout {
+            Some(idle_timeout) => tokio::time::timeout(idle_timeout, write).await,
+            None => Ok(write.await),
+        };
+
+        match result {
+            Ok(Ok(())) => _ = written.send(()).await,
+            Ok(Err(e)) => {
+                eprintln!("Response failed. Error {:?}", e);
+                break;
+            },
+            Err(_) => {
+                println!("Closing client with address {}, which isn't reading its responses", address);
+                ctx.limiter.idle_closed();
+                break;
+            },
+        }
+    }
+}
+
+// Waits for the client's Hello and answers it; returns whether the connection can be used
+async fn handshake<C: Listener>(connection: &mut C, codec: &str, address: &Address, ctx: &Context) -> bool {
+    let timeout = ctx.idle_timeout.unwrap_or(HANDSHAKE_TIMEOUT).min(HANDSHAKE_TIMEOUT);
+
+    let reply = match tokio::time::timeout(timeout, connection.listen::<Hello>()).await {
+        Ok(Ok(Some(_))) if !ctx.codecs.iter().any(|c| c.name() == codec) => {
+            rejected(format!("the {} codec isn't enabled on this server", codec))
+        },
+        Ok(Ok(Some(hello))) => negotiate(&hello, codec, ctx.max_pipelined),
+        Ok(Ok(None)) => return false,
+        Ok(Err(e)) if e.is::<DecodeError>() => rejected(format!("expected a handshake; {}", e)),
+        Ok(Err(_)) => return false,
+        Err(_) => rejected("handshake timed out".to_owned()),
+    };
+
+    let accepted = matches!(reply, HelloReply::Accepted { .. });
+    if !accepted {
+        println!("Failed handshake with client {}: {:?}", address, reply);
+    }
+
+    if let Err(e) = connection.respond(reply).await {
+        eprintln!("Handshake failed. Error {:?}", e);
+        return false;
+    }
+
+    accepted
+}
+
+// Checks a user's credentials, within the rate limit of the client
+async fn authenticate(user: &str, token: &str, client: &str, ctx: &Context) -> Response {
+    let received = Instant::now();
+
+    if let Err(retry_after) = ctx.rate_limiter.check(client, Op::Auth) {
+        let retry_after_ms = u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX);
+        return Response::RateLimited { retry_after_ms };
+    }
+
+    let (outcome, response) = if ctx.auth.verify(user, token) {
+        (Outcome::Ok, Response::Auth)
+    } else {
+        (Outcome::Err, Response::Error(Error::new(ErrorKind::Unauthorized, "invalid user or token")))
+    };
+
+    let event = StatEvent { op: Op::Auth, outcome, latency: received.elapsed() };
+    ctx.stats_producer.send(event).await;
+
+    response
+}
+
+// Handles a request, recording it in the audit log if it is audited
+async fn handle(req: Request, timeout_ms: Option<u64>, client: &str, address: &Address, ctx: &Arc<Context>) -> Response {
+    let entry = audit::entry(&req, client, address);
+    admit(req, timeout_ms, client, entry, ctx).await
+}
+
+// Records the audit entry of a request, if it is audited, with the request's result
+async fn record(entry: Option<AuditEntry>, response: &Response, ctx: &Context) {
+    let Some(mut entry) = entry else { return };
+    entry.result = audit::result(response);
+
+    let log = ctx.audit.clone();
+    match tokio::task::spawn_blocking(move || log.record(&entry)).await {
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => eprintln!("Audit log write failed. Error {:?}", e),
+        Err(e) => eprintln!("Audit log write failed. Error {:?}", e),
+    }
+}
+
+// Applies the access control, the rate limit and the time budget to a request, then processes it
+async fn admit(req: Request, timeout_ms: Option<u64>, client: &str, entry: Option<AuditEntry>, ctx: &Arc<Context>) -> Response {
+    let received = Instant::now();
+
+    // the client's time budget, capped by the server's own limit
+    let timeout = [timeout_ms.map(Duration::from_millis), ctx.request_timeout]
+        .into_iter()
+        .flatten()
+        .min();
+
+    if let Err(e) = ctx.acl.check(client, &req) {
+        println!("Denied request from client {}: {}", client, e.message);
+        let response = Response::Error(e);
+        record(entry, &response, ctx).await;
+        return response;
+    }
+
+    match ctx.rate_limiter.check(client, Op::of(&req)) {
+        Ok(()) => process(req, received, timeout, entry, ctx).await,
+        Err(retry_after) => {
+            println!("Rate limited client {}", client);
+            let retry_after_ms = u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX);
+            let response = Response::RateLimited { retry_after_ms };
+            record(entry, &response, ctx).await;
+            response
+        },
+    }
+}
+
+// Processes a request within its time budget and publishes its stats
+// Work past the deadline is aborted, except for writes and audited requests, which are left
+// to complete in the background so that the storage and the cache stay consistent.
+// Audited requests are recorded once processed, with their actual result even past the deadline.
+async fn process(req: Request, received: Instant, timeout: Option<Duration>, entry: Option<AuditEntry>, ctx: &Arc<Context>) -> Response {
+    let op = Op::of(&req);
+    let deadline = timeout.map(|t| received + t);
+
+    let (outcome, res) = if deadline.is_some_and(|d| d <= Instant::now()) {
+        let res = timed_out();
+        record(entry, &res, ctx).await;
+        (Outcome::Err, res)
+    } else {
+        let audited = entry.is_some();
+        let mut task = {
+            let (ctx, entry) = (ctx.clone(), entry.clone());
+            tokio::spawn(async move {
+                let processed = dispatch(req, &ctx).await;
+                record(entry, &processed.1, &ctx).await;
+                processed
+            })
+        };
+
+        let finished = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), &mut task).await,
+            None => Ok((&mut task).await),
+        };
+
+        match finished {
+            Ok(Ok(processed)) => processed,
+            Ok(Err(e)) => {
+                let res = Response::Error(Error::new(ErrorKind::Internal, e.to_string()));
+                record(entry, &res, ctx).await;
+                (Outcome::Err, res)
+            },
+            Err(_) => {
+                if !op.is_write() && !audited {
+                    task.abort();
+                }
+                (Outcome::Err, timed_out())
+            },
+        }
+    };
+
+    let event = StatEvent { op, outcome, latency: received.elapsed() };
+    ctx.stats_producer.send(event).await;
+
+    res
+}
+
+fn timed_out() -> Response {
+    Response::Error(Error::new(ErrorKind::Timeout, "request timed out"))
+}
+
+// Reports a failed write; writes that lost to a concurrent one weren't applied and can be retried
+fn write_failed(e: anyhow::Error) -> Response {
+    let kind = if is_conflict(&e) { ErrorKind::Conflict } else { ErrorKind::Internal };
+    Response::Error(Error::new(kind, e.to_string()))
+}
+
+// Processes a single request
+async fn dispatch(req: Request, ctx: &Context) -> (Outcome, Response) {
+    let dict = &ctx.dict;
+
+    match req {
+        Request::Get { key } => {
+            match dict.get(&key).await {
+                Ok(Some(val)) => (Outcome::Ok, Response::Get { val }),
+                Ok(None) => (Outcome::NotFound, Response::Error(Error::new(ErrorKind::NotFound, format!("key {} not found", key)))),
+                Err(e) => (Outcome::Err, Response::Error(Error::new(ErrorKind::Internal, e.to_string()))),
+            }
+        },
+        Request::Set { key, val } => {
+            match dict.set(&key, &val).await {
+                Ok(()) => (Outcome::Ok, Response::Set),
+                Err(e) => (Outcome::Err, write_failed(e)),
+            }
+        },
+        Request::Delete { key } => {
+            match dict.delete(&key).await {
+                Ok(true) => (Outcome::Ok, Response::Delete { existed: true }),
+                Ok(false) => (Outcome::NotFound, Response::Delete { existed: false }),
+                Err(e) => (Outcome::Err, write_failed(e)),
+            }
+        },
+        Request::Ping => (Outcome::Ok, Response::Pong),
+        // handled by the connection, as it changes its state
+        Request::Auth { .. } => (Outcome::Err, Response::Error(Error::new(ErrorKind::InvalidRequest, "unexpected authentication"))),
+        Request::Audit { query } => {
+            let log = ctx.audit.clone();
+            match tokio::task::spawn_blocking(move || log.query(&query)).await {
+                Ok(Ok(entries)) => (Outcome::Ok, Response::Audit { entries }),
+                Ok(Err(e)) => (Outcome::Err, Response::Error(Error::new(ErrorKind::Internal, e.to_string()))),
+                Err(e) => (Outcome::Err, Response::Error(Error::new(ErrorKind::Internal, e.to_string()))),
+            }
+        },
+        Request::Stats => {
+            let mut report = ctx.stats.report();
+            report.keys = dict.len();
+            report.storage_bytes = dict.storage_size();
+            report.cache = dict.cache_stats().await;
+            report.counters.extend(ctx.stats_producer.metrics());
+            report.counters.extend(ctx.limiter.metrics());
+            report.counters.extend(ctx.rate_limiter.metrics());
+            report.counters.extend(ctx.auth.metrics());
+            report.counters.extend(ctx.acl.metrics());
+            report.counters.extend(ctx.audit.metrics());
+
+            (Outcome::Ok, Response::Stats { stats: report })
+        },
+        Request::ResetStats => {
+            let reset_at = ctx.stats.reset();
+            (Outcome::Ok, Response::ResetStats { reset_at })
+        },
+    }
+}
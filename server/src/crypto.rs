@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm_siv::Aes256GcmSiv;
+use anyhow::{anyhow, bail, Context};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+// Stored data starts with the id of the key it was encrypted with
+const ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+
+/// Encryption of stored keys and values, with the keys of a local keyfile
+///
+/// Each keyfile line holds a key id and a hex 256-bit key; `#` starts a comment.
+/// The key with the highest id encrypts new data, older ones only decrypt data
+/// not yet re-encrypted, see `Db::reencrypt`.
+///
+/// ```text
+/// 1 8f3a...
+/// 2 c01d...
+/// ```
+///
+/// Values are encrypted with AES-256-GCM and bound to their key. Keys, when encrypted,
+/// use AES-256-GCM-SIV with a fixed nonce, so they can still be looked up: equal keys
+/// give equal ciphertexts. Whether keys are encrypted only applies to new data: `Db`
+/// stores entries with encrypted keys apart, and `Db::reencrypt` moves entries between modes.
+pub struct Cipher {
+    keys: BTreeMap<u32, Keys>,
+    current: u32,
+    encrypt_keys: bool,
+}
+
+// Separate subkeys for values and keys, derived from a keyfile key
+struct Keys {
+    values: Aes256Gcm,
+    keys: Aes256GcmSiv,
+}
+
+impl Keys {
+    fn derive(key: &[u8]) -> Self {
+        let subkey = |purpose: &[u8]| Sha256::new().chain_update(key).chain_update(purpose).finalize();
+
+        Self {
+            values: Aes256Gcm::new(&subkey(b"values")),
+            keys: Aes256GcmSiv::new(&subkey(b"keys")),
+        }
+    }
+}
+
+impl Cipher {
+    /// Loads the keys of a keyfile; keys are stored in plaintext unless `encrypt_keys` is set
+    pub fn load(path: &Path, encrypt_keys: bool) -> anyhow::Result<Self> {
+        let keys = read_keyfile(path)?
+            .into_iter()
+            .map(|(id, key)| (id, Keys::derive(&key)))
+            .collect::<BTreeMap<_, _>>();
+
+        let Some((&current, _)) = keys.last_key_value() else {
+            bail!("No key in keyfile {:?}", path);
+        };
+
+        Ok(Self { keys, current, encrypt_keys })
+    }
+
+    /// Appends a new random key to a keyfile, creating it if needed; returns its id
+    /// The new key becomes the current one once the server restarts.
+    pub fn generate(path: &Path) -> anyhow::Result<u32> {
+        let id = match path.exists() {
+            true => read_keyfile(path)?.last_key_value().map_or(1, |(id, _)| id + 1),
+            false => 1,
+        };
+
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+
+        let mut options = std::fs::OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(path)
+            .with_context(|| format!("Unable to open keyfile {:?}", path))?;
+        writeln!(file, "{} {}", id, hex::encode(key))?;
+
+        Ok(id)
+    }
+
+    /// Id of the key encrypting new data
+    pub fn current_key(&self) -> u32 {
+        self.current
+    }
+
+    /// Whether new data is stored with encrypted keys
+    pub fn encrypts_keys(&self) -> bool {
+        self.encrypt_keys
+    }
+
+    /// Encrypted form of a key, with the current encryption key
+    pub fn seal_key(&self, key: &[u8]) -> Vec<u8> {
+        self.seal_key_with(self.current, key)
+    }
+
+    /// Encrypted forms a key may have, current encryption key first
+    pub fn sealed_keys(&self, key: &[u8]) -> Vec<Vec<u8>> {
+        self.keys.keys()
+            .rev()
+            .map(|id| self.seal_key_with(*id, key))
+            .collect()
+    }
+
+    fn seal_key_with(&self, id: u32, key: &[u8]) -> Vec<u8> {
+        let sealed = self.keys[&id].keys
+            .encrypt(&[0u8; NONCE_LEN].into(), key)
+            .expect("key encryption failed");
+
+        [&id.to_be_bytes()[..], &sealed].concat()
+    }
+
+    /// Recovers a key from its encrypted form
+    pub fn open_key(&self, stored: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (keys, sealed) = self.split(stored)?;
+        keys.keys
+            .decrypt(&[0u8; NONCE_LEN].into(), sealed)
+            .map_err(|_| anyhow!("Unable to decrypt a stored key"))
+    }
+
+    /// Stored form of a value, with the current encryption key
+    /// The value can only be decrypted along with the key it is stored under.
+    pub fn seal_value(&self, key: &[u8], val: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let sealed = self.keys[&self.current].values
+            .encrypt(&nonce.into(), Payload { msg: val, aad: key })
+            .expect("value encryption failed");
+
+        [&self.current.to_be_bytes()[..], &nonce, &sealed].concat()
+    }
+
+    /// Recovers a value from its stored form
+    pub fn open_value(&self, key: &[u8], stored: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (keys, rest) = self.split(stored)?;
+        if rest.len() < NONCE_LEN {
+            bail!("Truncated stored value");
+        }
+
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        keys.values
+            .decrypt(nonce.into(), Payload { msg: sealed, aad: key })
+            .map_err(|_| anyhow!("Unable to decrypt a stored value"))
+    }
+
+    /// Whether encrypted data, a value or a key, was encrypted with an older key
+    pub fn is_stale(&self, stored: &[u8]) -> bool {
+        key_id(stored) != Some(self.current)
+    }
+
+    // Splits stored data into the keys it was encrypted with and the ciphertext
+    fn split<'a>(&self, stored: &'a [u8]) -> anyhow::Result<(&Keys, &'a [u8])> {
+        let id = key_id(stored).ok_or_else(|| anyhow!("Truncated stored data"))?;
+        let keys = self.keys.get(&id).ok_or_else(|| anyhow!("Stored data uses key {}, missing from the keyfile", id))?;
+
+        Ok((keys, &stored[ID_LEN..]))
+    }
+}
+
+fn key_id(stored: &[u8]) -> Option<u32> {
+    let id = stored.get(..ID_LEN)?;
+    Some(u32::from_be_bytes(id.try_into().ok()?))
+}
+
+fn read_keyfile(path: &Path) -> anyhow::Result<BTreeMap<u32, Vec<u8>>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read keyfile {:?}", path))?;
+
+    let mut keys = BTreeMap::new();
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let parsed = line.split_once(char::is_whitespace).and_then(|(id, key)| {
+            let id = id.parse::<u32>().ok()?;
+            let key = hex::decode(key.trim()).ok().filter(|key| key.len() == 32)?;
+            Some((id, key))
+        });
+
+        let Some((id, key)) = parsed else {
+            bail!("Invalid key at line {} of keyfile {:?}, expected an id and a hex 256-bit key", n + 1, path);
+        };
+
+        if keys.insert(id, key).is_some() {
+            bail!("Duplicate key id {} in keyfile {:?}", id, path);
+        }
+    }
+
+    Ok(keys)
+}
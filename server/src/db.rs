@@ -1,75 +1,176 @@
 use std::collections::HashMap;
 use std::hash::Hash;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use anyhow::Ok;
-use persy::{Persy, Config};
+use anyhow::{bail, Ok};
+use common::dto::CacheStats;
+use persy::{ByteVec, Persy, Config, PrepareError, Transaction, PE};
 use tokio::sync::RwLock;
 
+use crate::crypto::Cipher;
+
+// Entries re-encrypted per transaction
+const REENCRYPT_BATCH: usize = 256;
+
 // Wrapper over Persy, an in-process database with persistent disk storage
+// With a cipher, entries are stored encrypted in separate indexes, one per key mode; entries
+// still in the plaintext index or in the other mode are read as well, until `reencrypt` moves them over.
 // TODO: Sled as alternative
 pub struct Db<K, V> {
-    name: String,
+    indexes: Indexes,
+    path: PathBuf,
     db: Persy,
     cache: AsyncCache<K, Option<V>>,
+    keys: AtomicU64,
 }
 
 pub type DbResult<T> = anyhow::Result<T>;
 
+/// Whether a write failed because of a concurrent transaction on the same data, so it wasn't applied
+pub fn is_conflict(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<PE<PrepareError>>(),
+        Some(PE::PE(PrepareError::TransactionTimeout | PrepareError::VersionNotLastest)),
+    )
+}
+
+/// Conversion of keys and values to bytes, so they can be encrypted
+pub trait Plaintext: Sized {
+    fn to_bytes(&self) -> Vec<u8>;
+    fn from_bytes(bytes: Vec<u8>) -> DbResult<Self>;
+}
+
+impl Plaintext for String {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> DbResult<Self> {
+        Ok(String::from_utf8(bytes)?)
+    }
+}
+
+impl Plaintext for u8 {
+    fn to_bytes(&self) -> Vec<u8> {
+        vec![*self]
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> DbResult<Self> {
+        match bytes[..] {
+            [b] => Ok(b),
+            _ => bail!("Invalid stored u8"),
+        }
+    }
+}
+
+impl Plaintext for u64 {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_be_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> DbResult<Self> {
+        Ok(u64::from_be_bytes(bytes[..].try_into()?))
+    }
+}
+
 impl<K, V> Db<K, V>
-where K: persy::IndexType, V: persy::IndexType
+where K: persy::IndexType + Plaintext, V: persy::IndexType + Plaintext
 {
     pub fn open(path: &str, name: String) -> DbResult<Self> {
+        Self::open_with(path, name, None)
+    }
+
+    /// Opens the storage, encrypting the entries written from now on if a cipher is given
+    pub fn open_with(path: &str, name: String, cipher: Option<Arc<Cipher>>) -> DbResult<Self> {
         let db = Persy::open(path, Config::new())?;
+        let indexes = Indexes::new(name, cipher);
+
+        if indexes.cipher.is_some() {
+            for index in indexes.encrypted() {
+                if !db.exists_index(index)? {
+                    let mut tx = db.begin()?;
+                    tx.create_index::<ByteVec, ByteVec>(index, persy::ValueMode::Replace)?;
+                    tx.prepare()?.commit()?;
+                }
+            }
+        }
+
+        // the key count is kept in memory and updated on writes
+        let mut keys = 0;
+        if db.exists_index(&indexes.plain)? {
+            keys += db.range::<K, V, _>(&indexes.plain, ..)?.count() as u64;
+        }
+        for index in indexes.encrypted() {
+            if db.exists_index(index)? {
+                let encrypted = db.range::<ByteVec, ByteVec, _>(index, ..)?.count() as u64;
+                if encrypted > 0 && indexes.cipher.is_none() {
+                    bail!("Storage {} is encrypted, its keyfile is needed", path);
+                }
+                keys += encrypted;
+            }
+        }
 
         Ok(Self {
-            name,
+            indexes,
+            path: PathBuf::from(path),
             db,
             cache: AsyncCache::new(),
+            keys: AtomicU64::new(keys),
         })
     }
 
     pub fn create(path: &str, name: String) -> DbResult<Self> {
+        Self::create_with(path, name, None)
+    }
+
+    pub fn create_with(path: &str, name: String, cipher: Option<Arc<Cipher>>) -> DbResult<Self> {
         Persy::create(path)?;
 
-        let db = Self::open(path, name)?;
-        let mut tx = db.db.begin()?;
-        tx.create_index::<K, V>(&db.name, persy::ValueMode::Replace)?;
+        let db = Persy::open(path, Config::new())?;
+        let mut tx = db.begin()?;
+        tx.create_index::<K, V>(&name, persy::ValueMode::Replace)?;
         let prepared = tx.prepare()?;
         prepared.commit()?;
+        drop(db);
 
-        Ok(db)
+        Self::open_with(path, name, cipher)
     }
 
-    pub fn open_or_create(name: String) -> DbResult<Self>
+    pub fn open_or_create(name: String, cipher: Option<Arc<Cipher>>) -> DbResult<Self>
     {
         let path = format!("./{}.db", name);
         let path = std::path::Path::new(&path);
 
         let db: Db<K, V> = if path.exists() {
             println!("Opening storage");
-            Db::open(path.to_str().unwrap(), name)?
+            Db::open_with(path.to_str().unwrap(), name, cipher)?
         } else {
             println!("Creating storage");
-            Db::create(path.to_str().unwrap(), name)?
+            Db::create_with(path.to_str().unwrap(), name, cipher)?
         };
 
         Ok(db)
     }
 
     pub async fn get(&self, key: &K) -> DbResult<Option<V>>
-    where K: Eq + Hash + persy::IndexType, V: Clone + persy::IndexType
+    where K: Eq + Hash + Send + 'static, V: Send + 'static
     {
         // cached values are Options:
         // None         => the key is not cached
         // Some(None)   => the key is cached, without value
         // Some(val)    => the value is cached
-        if let Some(cached) = self.cache.get(&key).await {
+        if let Some(cached) = self.cache.get(key).await {
             println!("Cache hit");
             Ok(cached)
         } else {
             println!("Cache miss");
-            let mut tx = self.db.begin()?;
-            let val = tx.one::<K, V>(&self.name, &key)?;
+            let k = key.clone();
+            let val = self.blocking(move |db, indexes| {
+                let mut tx = db.begin()?;
+                Ok(indexes.find::<K, V>(&mut tx, &k)?.map(|(_, val)| val))
+            }).await?;
 
             self.cache.set(key.clone(), val.clone()).await;
 
@@ -78,39 +179,313 @@ where K: persy::IndexType, V: persy::IndexType
     }
 
     pub async fn set(&self, key: &K, val: &V) -> DbResult<()>
-    where K: Eq + Hash + persy::IndexType, V: Clone + persy::IndexType
+    where K: Eq + Hash + Send + 'static, V: Send + 'static
     {
-        let mut tx = self.db.begin()?;
-        tx.put::<K, V>(&self.name, key.to_owned(), val.to_owned())?;
-        tx.prepare()?.commit()?;
+        let (k, v) = (key.clone(), val.clone());
+        let is_new = self.blocking(move |db, indexes| {
+            let mut tx = db.begin()?;
+            let is_new = indexes.replace(&mut tx, k, v)?;
+            tx.prepare()?.commit()?;
+            Ok(is_new)
+        }).await?;
+
+        if is_new {
+            self.keys.fetch_add(1, Ordering::Relaxed);
+        }
 
         self.cache.set(key.clone(), Some(val.clone())).await;
 
         Ok(())
     }
+
+    /// Removes a key; returns whether it existed
+    pub async fn delete(&self, key: &K) -> DbResult<bool>
+    where K: Eq + Hash + Send + 'static, V: Send + 'static
+    {
+        let k = key.clone();
+        let existed = self.blocking(move |db, indexes| {
+            let mut tx = db.begin()?;
+            let Some((location, _)) = indexes.find::<K, V>(&mut tx, &k)? else {
+                return Ok(false);
+            };
+            indexes.remove::<K, V>(&mut tx, k, location)?;
+            tx.prepare()?.commit()?;
+            Ok(true)
+        }).await?;
+
+        if existed {
+            self.keys.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        self.cache.set(key.clone(), None).await;
+
+        Ok(existed)
+    }
+
+    /// Stores several key/value pairs in a single transaction
+    pub async fn set_many(&self, entries: &[(K, V)]) -> DbResult<()>
+    where K: Eq + Hash + Send + 'static, V: Send + 'static
+    {
+        let e = entries.to_vec();
+        let new_keys = self.blocking(move |db, indexes| {
+            let mut tx = db.begin()?;
+            let mut new_keys = 0;
+            for (key, val) in e {
+                if indexes.replace(&mut tx, key, val)? {
+                    new_keys += 1;
+                }
+            }
+            tx.prepare()?.commit()?;
+            Ok(new_keys)
+        }).await?;
+
+        self.keys.fetch_add(new_keys, Ordering::Relaxed);
+
+        for (key, val) in entries {
+            self.cache.set(key.clone(), Some(val.clone())).await;
+        }
+
+        Ok(())
+    }
+
+    /// Encrypts, with the current key and key mode, the entries stored in plaintext, with an older key
+    /// or in the other key mode
+    /// Runs in batches alongside other requests; returns the number of entries re-encrypted.
+    /// Once done, older keys can be removed from the keyfile.
+    pub async fn reencrypt(&self) -> DbResult<u64>
+    where K: Send + 'static, V: Send + 'static
+    {
+        self.blocking(move |db, indexes| {
+            let Some(cipher) = &indexes.cipher else {
+                return Ok(0);
+            };
+
+            // only keys are collected; the entries are read again when re-encrypted,
+            // as they may have been changed in the meantime
+            let plain = db.range::<K, V, _>(&indexes.plain, ..)?
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>();
+
+            let mut stale = Vec::new();
+            for index in indexes.encrypted() {
+                let keys = db.range::<ByteVec, ByteVec, _>(index, ..)?
+                    .filter_map(|(key, mut vals)| vals.next().filter(|val| indexes.is_stale(cipher, index, &key, val)).map(|_| key))
+                    .collect::<Vec<_>>();
+                stale.push((index, keys));
+            }
+
+            let mut reencrypted = 0;
+
+            for batch in plain.chunks(REENCRYPT_BATCH) {
+                let mut tx = db.begin()?;
+                for key in batch {
+                    if let Some(val) = tx.one::<K, V>(&indexes.plain, key)? {
+                        tx.remove::<K, V>(&indexes.plain, key.clone(), None)?;
+                        indexes.write(&mut tx, cipher, &key.to_bytes(), &val.to_bytes())?;
+                        reencrypted += 1;
+                    }
+                }
+                tx.prepare()?.commit()?;
+            }
+
+            for (index, keys) in stale {
+                for batch in keys.chunks(REENCRYPT_BATCH) {
+                    let mut tx = db.begin()?;
+                    for stored_key in batch {
+                        let Some(stored_val) = tx.one::<ByteVec, ByteVec>(index, stored_key)? else { continue };
+                        if !indexes.is_stale(cipher, index, stored_key, &stored_val) {
+                            continue;
+                        }
+
+                        let key = indexes.open_key(cipher, index, stored_key)?;
+                        let val = cipher.open_value(&key, &stored_val)?;
+                        tx.remove::<ByteVec, ByteVec>(index, stored_key.clone(), None)?;
+                        indexes.write(&mut tx, cipher, &key, &val)?;
+                        reencrypted += 1;
+                    }
+                    tx.prepare()?.commit()?;
+                }
+            }
+
+            Ok(reencrypted)
+        }).await
+    }
+
+    /// Number of stored keys
+    pub fn len(&self) -> u64 {
+        self.keys.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Size of the storage file, in bytes
+    pub fn storage_size(&self) -> u64 {
+        std::fs::metadata(&self.path).map_or(0, |m| m.len())
+    }
+
+    pub async fn cache_stats(&self) -> CacheStats {
+        self.cache.stats().await
+    }
+
+    // Runs Persy calls on the blocking thread pool, so they don't stall the async workers
+    async fn blocking<T, F>(&self, f: F) -> DbResult<T>
+    where F: FnOnce(&Persy, &Indexes) -> DbResult<T> + Send + 'static, T: Send + 'static
+    {
+        let db = self.db.clone();
+        let indexes = self.indexes.clone();
+        tokio::task::spawn_blocking(move || f(&db, &indexes)).await?
+    }
+}
+
+// Persy indexes of a Db: the plaintext one and, with a cipher, the encrypted ones:
+// one for entries with plaintext keys and one for entries with encrypted keys
+#[derive(Clone)]
+struct Indexes {
+    plain: String,
+    encrypted: String,
+    sealed: String,
+    cipher: Option<Arc<Cipher>>,
+}
+
+// Where an entry is stored: an encrypted index and the stored key
+enum Location {
+    Plain,
+    Encrypted(String, ByteVec),
+}
+
+impl Indexes {
+    fn new(name: String, cipher: Option<Arc<Cipher>>) -> Self {
+        Self {
+            encrypted: format!("{}_encrypted", name),
+            sealed: format!("{}_sealed", name),
+            plain: name,
+            cipher,
+        }
+    }
+
+    // Indexes of the encrypted entries, by key mode
+    fn encrypted(&self) -> [&String; 2] {
+        [&self.encrypted, &self.sealed]
+    }
+
+    // Reads an entry, wherever it is stored
+    fn find<K, V>(&self, tx: &mut Transaction, key: &K) -> DbResult<Option<(Location, V)>>
+    where K: persy::IndexType + Plaintext, V: persy::IndexType + Plaintext
+    {
+        if let Some(cipher) = &self.cipher {
+            let key = key.to_bytes();
+            let sealed = cipher.sealed_keys(&key)
+                .into_iter()
+                .map(|stored_key| (&self.sealed, stored_key));
+
+            for (index, stored_key) in std::iter::once((&self.encrypted, key.clone())).chain(sealed) {
+                let stored_key = ByteVec::from(stored_key);
+                if let Some(stored_val) = tx.one::<ByteVec, ByteVec>(index, &stored_key)? {
+                    let val = V::from_bytes(cipher.open_value(&key, &stored_val)?)?;
+                    return Ok(Some((Location::Encrypted(index.clone(), stored_key), val)));
+                }
+            }
+        }
+
+        Ok(tx.one::<K, V>(&self.plain, key)?.map(|val| (Location::Plain, val)))
+    }
+
+    // Writes an entry, replacing its previous one; returns whether the key is new
+    fn replace<K, V>(&self, tx: &mut Transaction, key: K, val: V) -> DbResult<bool>
+    where K: persy::IndexType + Plaintext, V: persy::IndexType + Plaintext
+    {
+        let found = self.find::<K, V>(tx, &key)?;
+        let is_new = found.is_none();
+
+        match &self.cipher {
+            Some(cipher) => {
+                if let Some((location, _)) = found {
+                    self.remove::<K, V>(tx, key.clone(), location)?;
+                }
+                self.write(tx, cipher, &key.to_bytes(), &val.to_bytes())?;
+            },
+            None => tx.put::<K, V>(&self.plain, key, val)?,
+        }
+
+        Ok(is_new)
+    }
+
+    // Writes an entry to the encrypted index of the current key mode, with the current key
+    fn write(&self, tx: &mut Transaction, cipher: &Cipher, key: &[u8], val: &[u8]) -> DbResult<()> {
+        let (index, stored_key) = match cipher.encrypts_keys() {
+            true => (&self.sealed, cipher.seal_key(key)),
+            false => (&self.encrypted, key.to_vec()),
+        };
+        let stored_val = ByteVec::from(cipher.seal_value(key, val));
+        tx.put::<ByteVec, ByteVec>(index, ByteVec::from(stored_key), stored_val)?;
+        Ok(())
+    }
+
+    // Whether an encrypted entry is to be re-encrypted: stored with an older key or in the other key mode
+    fn is_stale(&self, cipher: &Cipher, index: &str, stored_key: &[u8], stored_val: &[u8]) -> bool {
+        let sealed = index == self.sealed;
+        sealed != cipher.encrypts_keys() || cipher.is_stale(stored_val) || (sealed && cipher.is_stale(stored_key))
+    }
+
+    // Recovers the key of an encrypted entry
+    fn open_key(&self, cipher: &Cipher, index: &str, stored_key: &[u8]) -> DbResult<Vec<u8>> {
+        match index == self.sealed {
+            true => cipher.open_key(stored_key),
+            false => Ok(stored_key.to_vec()),
+        }
+    }
+
+    fn remove<K, V>(&self, tx: &mut Transaction, key: K, location: Location) -> DbResult<()>
+    where K: persy::IndexType, V: persy::IndexType
+    {
+        match location {
+            Location::Plain => tx.remove::<K, V>(&self.plain, key, None)?,
+            Location::Encrypted(index, stored_key) => tx.remove::<ByteVec, ByteVec>(&index, stored_key, None)?,
+        }
+        Ok(())
+    }
 }
 
 
 // Multi-reader, single-writer cache
 struct AsyncCache<K, V> {
-    cache: RwLock<HashMap<K, V>>
+    cache: RwLock<HashMap<K, V>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
 }
 
 impl<K, V> AsyncCache<K, V> {
     pub fn new() -> Self {
         Self {
-            cache: RwLock::new(HashMap::<K,V>::new())
+            cache: RwLock::new(HashMap::<K,V>::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
         }
     }
 
     pub async fn get(&self, key: &K) -> Option<V>
     where K: Eq + Hash, V: Clone
     {
-        self.cache
+        let cached = self.cache
             .read()
             .await
-            .get(&key)
-            .cloned()
+            .get(key)
+            .cloned();
+
+        let counter = if cached.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        cached
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.cache.read().await.len() as u64,
+        }
     }
 
     pub async fn set(&self, key: K, val: V)
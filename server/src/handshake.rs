@@ -0,0 +1,38 @@
+use common::dto::{Hello, HelloReply, FEATURES, MIN_PROTOCOL_VERSION, PROTOCOL_MAGIC, PROTOCOL_VERSION};
+
+/// Checks a client's Hello against what this server supports on the given codec
+/// Accepted clients are told how many requests they may have in flight.
+pub fn negotiate(hello: &Hello, codec: &str, max_pipelined: usize) -> HelloReply {
+    if hello.magic != PROTOCOL_MAGIC {
+        return rejected("expected a handshake; the client may predate protocol versioning".to_owned());
+    }
+
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.version) {
+        return rejected(format!(
+            "unsupported protocol version {}, the server supports versions {} to {}",
+            hello.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+        ));
+    }
+
+    if !hello.codecs.iter().any(|c| c == codec) {
+        return rejected(format!("the connection uses the {} codec, which the client doesn't support", codec));
+    }
+
+    let features = FEATURES
+        .iter()
+        .filter(|f| hello.features.iter().any(|h| h == *f))
+        .map(|f| f.to_string())
+        .collect();
+
+    HelloReply::Accepted {
+        version: hello.version,
+        codec: codec.to_owned(),
+        features,
+        max_pipelined: u32::try_from(max_pipelined).unwrap_or(u32::MAX),
+    }
+}
+
+/// Refuses a connection before its handshake, e.g. when over the connection limits
+pub fn rejected(reason: String) -> HelloReply {
+    HelloReply::Rejected { reason }
+}
@@ -1,2 +1,14 @@
 mod db;
 pub use db::*;
+
+pub mod acl;
+pub mod audit;
+pub mod auth;
+pub mod config;
+pub mod connection;
+pub mod crypto;
+pub mod handshake;
+pub mod limits;
+pub mod pipeline;
+pub mod ratelimit;
+pub mod stats;
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Rejected connections told why at once; further ones are closed without a word
+pub const MAX_REJECTING: usize = 16;
+
+/// Reason for refusing a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    TooManyConnections,
+    TooManyFromAddress,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::TooManyConnections => write!(f, "too many connections"),
+            Rejection::TooManyFromAddress => write!(f, "too many connections from this address"),
+        }
+    }
+}
+
+// Tracks open connections, globally and per client IP
+// A limit of 0 means unlimited.
+pub struct ConnectionLimiter {
+    max_total: usize,
+    max_per_ip: usize,
+    active: Mutex<HashMap<IpAddr, usize>>,
+    rejecting: AtomicUsize,
+    counters: Counters,
+}
+
+#[derive(Default)]
+struct Counters {
+    active: AtomicU64,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    idle_closed: AtomicU64,
+    handshake_failed: AtomicU64,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_total: usize, max_per_ip: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max_total,
+            max_per_ip,
+            active: Mutex::new(HashMap::new()),
+            rejecting: AtomicUsize::new(0),
+            counters: Counters::default(),
+        })
+    }
+
+    /// Registers a new connection, if the limits allow it
+    /// The connection is released when the returned guard is dropped.
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionGuard, Rejection> {
+        self.acquire(Some(ip))
+    }
+
+    /// Registers a new connection from the same host, without an address of its own,
+    /// if the total limit allows it
+    pub fn try_acquire_local(self: &Arc<Self>) -> Result<ConnectionGuard, Rejection> {
+        self.acquire(None)
+    }
+
+    fn acquire(self: &Arc<Self>, ip: Option<IpAddr>) -> Result<ConnectionGuard, Rejection> {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+
+        let total = self.counters.active.load(Ordering::Relaxed) as usize;
+        let from_ip = ip.and_then(|ip| active.get(&ip).copied()).unwrap_or_default();
+
+        let rejection = if self.max_total > 0 && total >= self.max_total {
+            Some(Rejection::TooManyConnections)
+        } else if self.max_per_ip > 0 && from_ip >= self.max_per_ip {
+            Some(Rejection::TooManyFromAddress)
+        } else {
+            None
+        };
+
+        if let Some(rejection) = rejection {
+            self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(rejection);
+        }
+
+        if let Some(ip) = ip {
+            *active.entry(ip).or_default() += 1;
+        }
+        self.counters.active.fetch_add(1, Ordering::Relaxed);
+        self.counters.accepted.fetch_add(1, Ordering::Relaxed);
+
+        Ok(ConnectionGuard { limiter: self.clone(), ip })
+    }
+
+    /// Reserves the right to tell a rejected connection why, unless MAX_REJECTING already are
+    /// This bounds the sockets and tasks a flood of excess connections ties up.
+    pub fn try_reject(self: &Arc<Self>) -> Option<RejectionGuard> {
+        let rejecting = self.rejecting.fetch_add(1, Ordering::Relaxed);
+        let guard = RejectionGuard { limiter: self.clone() };
+
+        (rejecting < MAX_REJECTING).then_some(guard)
+    }
+
+    /// Counts a connection closed for inactivity
+    pub fn idle_closed(&self) {
+        self.counters.idle_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a connection closed for a failed handshake
+    pub fn handshake_failed(&self) {
+        self.counters.handshake_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Connection metrics, as named counters
+    pub fn metrics(&self) -> [(String, u64); 5] {
+        [
+            ("connections.active".to_owned(), self.counters.active.load(Ordering::Relaxed)),
+            ("connections.accepted".to_owned(), self.counters.accepted.load(Ordering::Relaxed)),
+            ("connections.rejected".to_owned(), self.counters.rejected.load(Ordering::Relaxed)),
+            ("connections.idle_closed".to_owned(), self.counters.idle_closed.load(Ordering::Relaxed)),
+            ("connections.handshake_failed".to_owned(), self.counters.handshake_failed.load(Ordering::Relaxed)),
+        ]
+    }
+
+    fn release(&self, ip: Option<IpAddr>) {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(ip) = ip {
+            if let Some(count) = active.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    active.remove(&ip);
+                }
+            }
+        }
+
+        self.counters.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// An open connection slot, released on drop
+pub struct ConnectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+/// A rejected connection being told why, released on drop
+pub struct RejectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+}
+
+impl Drop for RejectionGuard {
+    fn drop(&mut self) {
+        self.limiter.rejecting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
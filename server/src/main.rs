@@ -1,12 +1,23 @@
-use std::{net::SocketAddr, str::FromStr, sync::Arc};
+use std::{path::{Path, PathBuf}, str::FromStr, sync::Arc, time::Duration};
 use clap::Parser;
-use tokio::{net::TcpListener, sync::mpsc::{unbounded_channel, UnboundedSender}};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{net::TcpListener, time::{Interval, MissedTickBehavior}};
 
-use common::{dto::{Request, Response}, net::{BincodeConnection, Listener}};
+use common::codec::CodecKind;
+use common::net::{Address, Transport};
+use common::tls::TlsServer;
 use server::Db;
-
-const OK_GET: u8 = 0;
-const BAD_GET: u8 = 1;
+use server::acl::AccessControl;
+use server::audit::AuditLog;
+use server::auth::{Authenticator, UserConfig};
+use server::config::Config;
+use server::connection::{accept, acquire_connection, Context};
+use server::crypto::Cipher;
+use server::limits::ConnectionLimiter;
+use server::pipeline::{stats_channel, Overflow, StatsProducer};
+use server::ratelimit::RateLimiter;
+use server::stats::{Stats, RESET_AT_ID};
 
 
 #[derive(Parser)]
@@ -14,138 +25,422 @@ const BAD_GET: u8 = 1;
 #[command(author, version, propagate_version=true)]
 struct Cli {
     #[arg(short, long, value_name="ADDRESS")]
-    #[arg(help="Server address; alternatively, set the DICT_ADDR env var")]
+    #[arg(help="Server address, or unix:/path for a Unix socket; alternatively, set the DICT_ADDR env var")]
     address: Option<String>,
+
+    #[arg(long, value_name="PATH")]
+    #[arg(help="Unix socket to listen on, in addition to or instead of the TCP address")]
+    #[cfg(unix)]
+    unix_socket: Option<PathBuf>,
+
+    #[arg(long, value_name="MODE", default_value="660", value_parser=parse_mode)]
+    #[arg(help="Octal file permissions of the Unix socket")]
+    #[cfg(unix)]
+    unix_socket_mode: u32,
+
+    #[arg(short, long, value_name="FILE")]
+    #[arg(help="TOML config file with rate limits, users and ACLs")]
+    config: Option<PathBuf>,
+
+    #[arg(long, value_name="COUNT", default_value_t=1000)]
+    #[arg(help="Store the stats every COUNT requests; 0 disables")]
+    stats_flush_requests: u64,
+
+    #[arg(long, value_name="SECS", default_value_t=10)]
+    #[arg(help="Store the stats every SECS seconds, if there were requests; 0 disables")]
+    stats_flush_secs: u64,
+
+    #[arg(long, value_name="COUNT", default_value_t=10_000)]
+    #[arg(help="Maximum number of stats events waiting to be recorded; at least 1")]
+    stats_queue_capacity: usize,
+
+    #[arg(long, value_enum, default_value_t=Overflow::Aggregate)]
+    #[arg(help="What to do with stats events when the queue is full")]
+    stats_overflow: Overflow,
+
+    #[arg(long, value_name="COUNT", default_value_t=1024)]
+    #[arg(help="Maximum number of open connections; 0 is unlimited")]
+    max_connections: usize,
+
+    #[arg(long, value_name="COUNT", default_value_t=64)]
+    #[arg(help="Maximum number of open connections per client IP; 0 is unlimited")]
+    max_connections_per_ip: usize,
+
+    #[arg(long, value_name="SECS", default_value_t=300)]
+    #[arg(help="Close connections without requests for SECS seconds; 0 disables")]
+    idle_timeout_secs: u64,
+
+    #[arg(long, value_name="SECS", default_value_t=30)]
+    #[arg(help="Maximum time to process a request, lowered by the client's own timeout; 0 disables")]
+    request_timeout_secs: u64,
+
+    #[arg(long, value_name="COUNT", default_value_t=64)]
+    #[arg(help="Maximum number of requests processed at once per connection")]
+    max_pipelined: usize,
+
+    #[arg(long, value_name="CODEC", value_delimiter=',', default_values_t=CodecKind::ALL, value_parser=CodecKind::from_str)]
+    #[arg(help="Codecs clients may use: json, bincode, msgpack or cbor")]
+    codecs: Vec<CodecKind>,
+
+    #[arg(long, value_name="FILE", requires="tls_key")]
+    #[arg(help="PEM certificate chain; enables TLS, all TCP clients must then use it")]
+    tls_cert: Option<PathBuf>,
+
+    #[arg(long, value_name="FILE", requires="tls_cert")]
+    #[arg(help="PEM private key of the TLS certificate")]
+    tls_key: Option<PathBuf>,
+
+    #[arg(long, value_name="FILE", requires="tls_cert")]
+    #[arg(help="PEM file of the CA certificates for client certificates; clients must then present one")]
+    tls_client_ca: Option<PathBuf>,
+
+    #[arg(long, value_name="FILE", default_value="audit.log")]
+    #[arg(help="Audit log of the mutating operations; rotated files get a numbered suffix")]
+    audit_log: PathBuf,
+
+    #[arg(long, value_name="BYTES", default_value_t=10 * 1024 * 1024)]
+    #[arg(help="Rotate the audit log once it grows past BYTES")]
+    audit_max_bytes: u64,
+
+    #[arg(long, value_name="COUNT", default_value_t=10)]
+    #[arg(help="Number of rotated audit log files to keep")]
+    audit_max_files: usize,
+
+    #[arg(long, value_name="FILE")]
+    #[arg(help="Keyfile of the keys encrypting the stored values; enables encryption at rest")]
+    keyfile: Option<PathBuf>,
+
+    #[arg(long, requires="keyfile")]
+    #[arg(help="Encrypt the stored keys as well, deterministically so they can be looked up; existing entries are converted in the background")]
+    encrypt_keys: bool,
+
+    #[arg(long, requires="keyfile")]
+    #[arg(help="Append a new key to the keyfile and exit; data is re-encrypted with it on next start")]
+    generate_key: bool,
+
+    #[arg(long, value_name="USER")]
+    #[arg(help="Print the config entry of USER, with the token read from stdin, and exit")]
+    hash_token: Option<String>,
+}
+
+// When to snapshot the in-memory stats to the stats DB
+// The stats are always stored on shutdown.
+#[derive(Clone, Copy)]
+struct FlushPolicy {
+    requests: u64,
+    interval: Duration,
 }
 
 #[tokio::main]
 pub async fn main() -> anyhow::Result<()> {
     // parse args
     let cli = Cli::parse();
+
+    if let Some(user) = &cli.hash_token {
+        return print_user_config(user);
+    }
+
+    if let (true, Some(keyfile)) = (cli.generate_key, &cli.keyfile) {
+        let id = Cipher::generate(keyfile)?;
+        println!("Added key {} to keyfile {:?}", id, keyfile);
+        return Ok(());
+    }
     
-    let address = match cli.address {
-        Some(address) => address,
-        None => std::env::var("DICT_ADDR").expect("Provide an address or set the DICT_ADDR env var"),
+    let address = cli.address.clone().or_else(|| std::env::var("DICT_ADDR").ok());
+    if address.is_none() && unix_socket(&cli).is_none() {
+        anyhow::bail!("Provide an address or a Unix socket, or set the DICT_ADDR env var");
+    }
+
+    let address = address.map(|address| Address::from_str(&address)
+        .expect("Unable to parse address"));
+
+    // a unix: address is another way to name the Unix socket
+    let (address, unix_path) = match (address, unix_socket(&cli)) {
+        (Some(Address::Tcp(address)), path) => (Some(address), path.map(Path::to_owned)),
+        (Some(Address::Unix(path)), None) => (None, Some(path)),
+        (Some(Address::Unix(_)), Some(_)) => anyhow::bail!("Provide the Unix socket either as the address or with --unix-socket"),
+        (None, path) => (None, path.map(Path::to_owned)),
+    };
+
+    #[cfg(not(unix))]
+    if unix_path.is_some() {
+        anyhow::bail!("Unix sockets aren't available on this platform");
+    }
+
+    let config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    let tls = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => Some(TlsServer::new(cert, key, cli.tls_client_ca.as_deref())?),
+        _ => None,
+    };
+
+    let cipher = match &cli.keyfile {
+        Some(keyfile) => Some(Arc::new(Cipher::load(keyfile, cli.encrypt_keys)?)),
+        None => None,
     };
-    
-    let address = SocketAddr::from_str(&address)
-        .expect("Unable to parse address");
 
     // makes dbs
-    let dict = Arc::new(Db::<String, String>::open_or_create("dict".to_owned())?);
-    let stats = Arc::new(Db::<u8, u64>::open_or_create("stats".to_owned())?);
+    let dict = Arc::new(Db::<String, String>::open_or_create("dict".to_owned(), cipher.clone())?);
+    let stats_db = Arc::new(Db::<u8, u64>::open_or_create("stats".to_owned(), cipher.clone())?);
+
+    // data stored in plaintext or with an older key is re-encrypted in the background
+    if let Some(cipher) = &cipher {
+        println!("Encrypting storage with key {}", cipher.current_key());
+        tokio::spawn(reencrypt(dict.clone(), "dict"));
+        tokio::spawn(reencrypt(stats_db.clone(), "stats"));
+    }
+
+    let audit = Arc::new(AuditLog::open(&cli.audit_log, cli.audit_max_bytes, cli.audit_max_files)?);
+
+    // in-memory stats, restored from the last snapshot
+    let stats = Arc::new(Stats::new());
+    restore_stats(&stats_db, &stats).await?;
 
     // start server and handle clients
     // each client request is followed by a server response
-    let listener = TcpListener::bind(address).await?;
-    println!("Listening on {:?}", listener.local_addr());
+    let listener = match address {
+        Some(address) => {
+            let listener = TcpListener::bind(address).await?;
+            println!("Listening on {:?}", listener.local_addr());
+            Some(listener)
+        },
+        None => None,
+    };
+
+    #[cfg(unix)]
+    let unix_listener = match &unix_path {
+        Some(path) => Some(bind_unix(path, cli.unix_socket_mode)?),
+        None => None,
+    };
+    #[cfg(not(unix))]
+    let unix_listener = None;
+
+    // every processed request is published as a stats event
+    let policy = FlushPolicy {
+        requests: cli.stats_flush_requests,
+        interval: Duration::from_secs(cli.stats_flush_secs),
+    };
+    let stats_producer = make_stats_handler(stats_db.clone(), stats.clone(), policy, cli.stats_queue_capacity.max(1), cli.stats_overflow);
+
+    let ctx = Arc::new(Context {
+        dict,
+        stats: stats.clone(),
+        stats_producer,
+        limiter: ConnectionLimiter::new(cli.max_connections, cli.max_connections_per_ip),
+        rate_limiter: RateLimiter::new(config.rate_limit),
+        auth: Authenticator::new(config.users),
+        acl: AccessControl::new(config.acl),
+        audit,
+        idle_timeout: (cli.idle_timeout_secs > 0).then(|| Duration::from_secs(cli.idle_timeout_secs)),
+        request_timeout: (cli.request_timeout_secs > 0).then(|| Duration::from_secs(cli.request_timeout_secs)),
+        max_pipelined: cli.max_pipelined.max(1),
+        codecs: cli.codecs.clone(),
+        tls,
+    });
+
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
 
-    // task req: count get reqs: total, ok, nok
-    // on every get broadcast true or false, depending on the result
-    let stats_producer = make_stats_handler(stats.clone());
-    
     loop {
-        let (socket, address) = listener.accept().await?;
-        println!("Accepted client with address {:?}", address);
-
-        let mut connection = BincodeConnection::from_socket(socket);
-        let dict = dict.clone();
-        let s = stats.clone();
-        let stats_producer = stats_producer.clone();
-
-        tokio::spawn(async move {
-            while let Ok(Some(req)) = connection.listen().await {
-                println!("Processing request {:?}", req);
-                let res = match req {
-                    Request::Get { key } => {
-                        match dict.get(&key).await {
-                            Ok(Some(val)) => {
-                                _ = stats_producer.send(true)
-                                    .map_err(|e| eprintln!("Unable to upload stats. Error: {}", e));
-                                
-                                    Response::Get { ok: true, val: Some(val), err: None }
-                            },
-                            Ok(None) => {
-                                _ = stats_producer.send(false)
-                                    .map_err(|e| eprintln!("Unable to upload stats. Error: {}", e));
-
-                                Response::Get { ok: false, val: None, err: Some(String::from("not found")) }
-                            },
-                            Err(e) => {
-                                _ = stats_producer.send(false)
-                                    .map_err(|e| eprintln!("Unable to upload stats. Error: {}", e));
-
-                                Response::Get { ok: false, val: None, err: Some(e.to_string()) }
-                            }
-                        }
-                    },
-                    Request::Set { key, val } => {
-                        match dict.set(&key, &val).await {
-                            Ok(()) => Response::Set { ok: true, err: None },
-                            Err(e) => Response::Set { ok: false, err: Some(e.to_string()) }
-                        }
-                    },
-                    Request::Stats => {
-                        let mut response = Response::Stats { ok: false, total: None, good: None, bad: None };
-
-                        // NOTE: this is actually a bug in an async environment
-                        // the stats DB needs a locking/transaction mechanism
-                        // to retrieve both values in a single lock
-                        if let Ok(Some(good_count)) = s.get(&OK_GET).await {
-                            if let Ok(Some(bad_count)) = s.get(&BAD_GET).await {
-                                response = Response::Stats { ok: true, total: Some(good_count + bad_count), good: Some(good_count), bad: Some(bad_count) };
-                            }
-                        };
-
-                        response
-                    },
-                };
-
-                println!("Responding back");
-                if let Err(e) = connection.respond(res).await {
-                    eprintln!("Response failed. Error {:?}", e)
-                }
-            }
-        });
+        let (transport, address) = tokio::select! {
+            accepted = accept_tcp(listener.as_ref()) => accepted?,
+            accepted = accept_unix(unix_listener.as_ref(), unix_path.as_deref()) => accepted?,
+            _ = &mut shutdown => break,
+        };
+
+        // the slot is reserved before the TLS handshake, so that excess connections are turned away early
+        let admission = acquire_connection(&ctx.limiter, &address);
+        match &admission {
+            Ok(_) => println!("Accepted client with address {}", address),
+            Err(rejection) => println!("Rejected client with address {}: {}", address, rejection),
+        }
+
+        let ctx = ctx.clone();
+        tokio::spawn(accept(transport, address, admission, ctx));
+    }
+
+    println!("Shutting down");
+    if let Some(path) = &unix_path {
+        _ = std::fs::remove_file(path);
+    }
+    persist_stats(&stats_db, &stats).await;
+
+    Ok(())
+}
+
+// Unix socket to listen on, if any
+#[cfg(unix)]
+fn unix_socket(cli: &Cli) -> Option<&Path> {
+    cli.unix_socket.as_deref()
+}
+
+// Unix sockets aren't available on this platform
+#[cfg(not(unix))]
+fn unix_socket(_cli: &Cli) -> Option<&Path> {
+    None
+}
+
+// Never created, as there are no Unix sockets on this platform
+#[cfg(not(unix))]
+enum UnixListener {}
+
+// Binds a Unix socket with the given file permissions, replacing the one left by a previous run
+// The socket is bound in a private directory and moved into place once its permissions are set,
+// so that it is never reachable with the default ones.
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if !m.file_type().is_socket() => anyhow::bail!("{} exists and isn't a socket", path.display()),
+        _ => {},
+    }
+
+    let name = path.file_name().ok_or_else(|| anyhow::anyhow!("Bad Unix socket path {}", path.display()))?;
+    let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+    let bound = (|| {
+        let staged = private.join(name);
+        let listener = UnixListener::bind(&staged)?;
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&staged, path)?;
+        Ok::<_, anyhow::Error>(listener)
+    })();
+    _ = std::fs::remove_dir_all(&private);
+
+    let listener = bound?;
+    println!("Listening on unix:{}", path.display());
+
+    Ok(listener)
+}
+
+// Accepts a TCP connection, or waits forever without a TCP listener
+async fn accept_tcp(listener: Option<&TcpListener>) -> std::io::Result<(Transport, Address)> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(socket, address)| (Transport::Tcp(socket), address.into())),
+        None => std::future::pending().await,
     }
 }
 
-// Creates an async and unbounded multi-producer / single-consumer channel for publishing stats.
-fn make_stats_handler(s: Arc<Db<u8, u64>>) -> UnboundedSender<bool> {
-    let (stats_producer, mut stats_recorder) = unbounded_channel::<bool>();
+// Accepts a Unix socket connection, or waits forever without a Unix listener
+// Unix socket peers are unnamed, so they are known by the socket path.
+#[cfg(unix)]
+async fn accept_unix(listener: Option<&UnixListener>, path: Option<&Path>) -> std::io::Result<(Transport, Address)> {
+    match (listener, path) {
+        (Some(listener), Some(path)) => listener.accept().await.map(|(socket, _)| (Transport::Unix(socket), Address::Unix(path.to_owned()))),
+        _ => std::future::pending().await,
+    }
+}
+
+// Waits forever, as there are no Unix sockets on this platform
+#[cfg(not(unix))]
+async fn accept_unix(_listener: Option<&UnixListener>, _path: Option<&Path>) -> std::io::Result<(Transport, Address)> {
+    std::future::pending().await
+}
+
+// Parses octal file permissions
+#[cfg(unix)]
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8).map_err(|e| format!("expected octal permissions like 660: {}", e))
+}
+
+// Re-encrypts a storage with the current key, logging the outcome
+async fn reencrypt<K, V>(db: Arc<Db<K, V>>, name: &str)
+where K: persy::IndexType + server::Plaintext + Send + 'static, V: persy::IndexType + server::Plaintext + Send + 'static
+{
+    match db.reencrypt().await {
+        Ok(0) => {},
+        Ok(count) => println!("Re-encrypted {} entries of storage {}", count, name),
+        Err(e) => eprintln!("Re-encryption of storage {} failed. Error {:?}", name, e),
+    }
+}
+
+// Prints the config entry of a user, hashing the token read from stdin
+fn print_user_config(user: &str) -> anyhow::Result<()> {
+    let mut token = String::new();
+    std::io::stdin().read_line(&mut token)?;
+
+    let entry = UserConfig::new(token.trim_end_matches(['\r', '\n']));
+    println!("[users.{:?}]\nsalt = {:?}\nhash = {:?}", user, entry.salt, entry.hash);
+
+    Ok(())
+}
+
+// Creates a bounded multi-producer / single-consumer channel for publishing stats.
+// Events are counted in memory and the counters are snapshotted to the stats DB per the flush policy.
+fn make_stats_handler(s: Arc<Db<u8, u64>>, stats: Arc<Stats>, policy: FlushPolicy, capacity: usize, overflow: Overflow) -> StatsProducer {
+    let (stats_producer, mut stats_recorder) = stats_channel(capacity, overflow);
 
     tokio::spawn(async move {
-        while let Some(stat) = stats_recorder.recv().await {
-            match stat {
-                // NOTE: this is a bug
-                // needs locking/transaction       
-                true => {
-                    println!("Counting a succesful Get");
-                    match s.get(&OK_GET).await {
-                        Ok(count) => {
-                            println!("Current count {:?}", count);
-                            let count = count.map_or(1, |c| c + 1);
-                            _ = s
-                                .set(&OK_GET, &count)
-                                .await
-                                .map_err(|e| eprintln!("Unable to store stat update. Error: {}", e));
-                        },
-                        Err(e) => println!("Db get err {:?}", e)
+        let mut ticker = (!policy.interval.is_zero()).then(|| {
+            let mut ticker = tokio::time::interval(policy.interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticker
+        });
+        let mut pending = 0;
+
+        loop {
+            tokio::select! {
+                event = stats_recorder.recv() => {
+                    let Some(event) = event else { break };
+                    stats.record(&event);
+
+                    pending += 1 + stats_recorder.drain_aggregate(&stats);
+                    if policy.requests > 0 && pending >= policy.requests {
+                        persist_stats(&s, &stats).await;
+                        pending = 0;
                     }
                 },
-                false => {
-                    println!("Counting a failed Get");
-                    if let Ok(count) = s.get(&BAD_GET).await {
-                        let count = count.map_or(1, |c| c + 1);
-                        _ = s
-                            .set(&BAD_GET, &count)
-                            .await
-                            .map_err(|e| eprintln!("Unable to store stat update. Error: {}", e));
-                    }
-                }
+                _ = tick(&mut ticker), if pending > 0 => {
+                    stats_recorder.drain_aggregate(&stats);
+                    persist_stats(&s, &stats).await;
+                    pending = 0;
+                },
             }
         }
+
+        stats_recorder.drain_aggregate(&stats);
+        persist_stats(&s, &stats).await;
     });
 
     stats_producer
 }
+
+// Waits for the next tick, or forever if there is no ticker
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => _ = ticker.tick().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Writes a snapshot of the stats counters to the stats DB
+async fn persist_stats(s: &Db<u8, u64>, stats: &Stats) {
+    println!("Storing stats snapshot");
+    _ = s
+        .set_many(&stats.counters())
+        .await
+        .map_err(|e| eprintln!("Unable to store stats snapshot. Error: {}", e));
+}
+
+// Loads the persisted stats counters
+async fn restore_stats(s: &Db<u8, u64>, stats: &Stats) -> anyhow::Result<()> {
+    let ids = stats.counters()
+        .into_iter()
+        .map(|(id, _)| id)
+        .chain([RESET_AT_ID]);
+
+    let mut counters = Vec::new();
+    for id in ids {
+        if let Some(count) = s.get(&id).await? {
+            counters.push((id, count));
+        }
+    }
+
+    stats.restore(&counters);
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use clap::ValueEnum;
+use tokio::sync::mpsc::{channel, Receiver, Sender, error::TrySendError};
+
+use crate::stats::{StatEvent, Stats};
+
+/// What to do with a stats event when the queue is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Overflow {
+    /// Wait for room in the queue, slowing down the request
+    Block,
+    /// Discard the event and count it as dropped
+    Drop,
+    /// Count the event in an overflow aggregate, merged by the consumer
+    Aggregate,
+}
+
+/// Creates a bounded multi-producer / single-consumer queue for stats events
+pub fn stats_channel(capacity: usize, overflow: Overflow) -> (StatsProducer, StatsConsumer) {
+    let (sender, receiver) = channel(capacity);
+    let shared = Arc::new(Shared {
+        capacity,
+        aggregate: Stats::new(),
+        blocked: AtomicU64::new(0),
+        dropped: AtomicU64::new(0),
+        aggregated: AtomicU64::new(0),
+    });
+
+    let producer = StatsProducer { sender, overflow, shared: shared.clone() };
+    let consumer = StatsConsumer { receiver, shared };
+
+    (producer, consumer)
+}
+
+struct Shared {
+    capacity: usize,
+    aggregate: Stats,
+    blocked: AtomicU64,
+    dropped: AtomicU64,
+    aggregated: AtomicU64,
+}
+
+/// Sending half of the stats queue
+#[derive(Clone)]
+pub struct StatsProducer {
+    sender: Sender<StatEvent>,
+    overflow: Overflow,
+    shared: Arc<Shared>,
+}
+
+impl StatsProducer {
+    /// Publishes an event, applying the overflow policy if the queue is full
+    pub async fn send(&self, event: StatEvent) {
+        let event = match self.sender.try_send(event) {
+            Ok(()) => return,
+            Err(TrySendError::Closed(_)) => {
+                eprintln!("Unable to upload stats. Error: stats queue closed");
+                return;
+            },
+            Err(TrySendError::Full(event)) => event,
+        };
+
+        match self.overflow {
+            Overflow::Block => {
+                self.shared.blocked.fetch_add(1, Ordering::Relaxed);
+                _ = self.sender.send(event)
+                    .await
+                    .map_err(|e| eprintln!("Unable to upload stats. Error: {}", e));
+            },
+            Overflow::Drop => {
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            },
+            Overflow::Aggregate => {
+                self.shared.aggregate.record(&event);
+                self.shared.aggregated.fetch_add(1, Ordering::Relaxed);
+            },
+        }
+    }
+
+    /// Number of events waiting in the queue
+    pub fn backlog(&self) -> u64 {
+        (self.shared.capacity - self.sender.capacity()) as u64
+    }
+
+    /// Queue metrics, as named counters
+    pub fn metrics(&self) -> [(String, u64); 5] {
+        [
+            ("pipeline.backlog".to_owned(), self.backlog()),
+            ("pipeline.capacity".to_owned(), self.shared.capacity as u64),
+            ("pipeline.blocked".to_owned(), self.shared.blocked.load(Ordering::Relaxed)),
+            ("pipeline.dropped".to_owned(), self.shared.dropped.load(Ordering::Relaxed)),
+            ("pipeline.aggregated".to_owned(), self.shared.aggregated.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+/// Receiving half of the stats queue
+pub struct StatsConsumer {
+    receiver: Receiver<StatEvent>,
+    shared: Arc<Shared>,
+}
+
+impl StatsConsumer {
+    /// Waits for the next event; returns None once all producers are gone
+    pub async fn recv(&mut self) -> Option<StatEvent> {
+        self.receiver.recv().await
+    }
+
+    /// Moves the aggregated overflow events into the given stats
+    /// Returns the number of moved events.
+    pub fn drain_aggregate(&self, stats: &Stats) -> u64 {
+        stats.merge(&self.shared.aggregate)
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::config::{Limit, RateLimitConfig};
+use crate::stats::Op;
+
+// Idle buckets are pruned once the table grows past this many entries
+const PRUNE_THRESHOLD: usize = 4096;
+
+// Token bucket rate limiter, keyed by client identity and operation
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(String, Op), Bucket>>,
+    limited: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+            limited: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes a token for the request, or returns how long to wait for one
+    pub fn check(&self, client: &str, op: Op) -> Result<(), Duration> {
+        let Some(limit) = self.config.limit(client, op) else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+
+        let result = buckets
+            .entry((client.to_owned(), op))
+            .or_insert_with(|| Bucket::new(limit, now))
+            .take(limit, now);
+
+        if result.is_err() {
+            self.limited.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    /// Rate limiter metrics, as named counters
+    pub fn metrics(&self) -> [(String, u64); 1] {
+        [("ratelimit.limited".to_owned(), self.limited.load(Ordering::Relaxed))]
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    burst: f64,
+    rate: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: Limit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            burst: limit.burst as f64,
+            rate: limit.rate,
+            updated: now,
+        }
+    }
+
+    fn take(&mut self, limit: Limit, now: Instant) -> Result<(), Duration> {
+        // the limit may have changed since the bucket was created
+        self.burst = limit.burst as f64;
+        self.rate = limit.rate;
+
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        if self.rate <= 0.0 {
+            return Err(Duration::MAX);
+        }
+
+        Err(Duration::try_from_secs_f64((1.0 - self.tokens) / self.rate).unwrap_or(Duration::MAX))
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.burst
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common::dto::{Latency, OpStats, Request, StatsReport};
+use serde::Deserialize;
+
+/// Request kinds tracked by the stats
+/// The discriminants are part of the persisted counter ids, new kinds go at the end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+    Get = 0,
+    Set = 1,
+    Stats = 2,
+    ResetStats = 3,
+    Delete = 4,
+    Ping = 5,
+    Auth = 6,
+    Audit = 7,
+}
+
+impl Op {
+    pub const ALL: [Op; 8] = [Op::Get, Op::Set, Op::Stats, Op::ResetStats, Op::Delete, Op::Ping, Op::Auth, Op::Audit];
+
+    pub fn of(req: &Request) -> Self {
+        match req {
+            Request::Get { .. } => Op::Get,
+            Request::Set { .. } => Op::Set,
+            Request::Stats => Op::Stats,
+            Request::ResetStats => Op::ResetStats,
+            Request::Delete { .. } => Op::Delete,
+            Request::Ping => Op::Ping,
+            Request::Auth { .. } => Op::Auth,
+            Request::Audit { .. } => Op::Audit,
+        }
+    }
+
+    /// Whether the request changes the dictionary
+    pub fn is_write(&self) -> bool {
+        matches!(self, Op::Set | Op::Delete)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Get => "get",
+            Op::Set => "set",
+            Op::Stats => "stats",
+            Op::ResetStats => "reset_stats",
+            Op::Delete => "delete",
+            Op::Ping => "ping",
+            Op::Auth => "auth",
+            Op::Audit => "audit",
+        }
+    }
+}
+
+/// Result of a processed request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Ok = 0,
+    NotFound = 1,
+    Err = 2,
+}
+
+impl Outcome {
+    pub const ALL: [Outcome; 3] = [Outcome::Ok, Outcome::NotFound, Outcome::Err];
+}
+
+/// A single processed request, as published by the connection tasks
+#[derive(Clone, Copy, Debug)]
+pub struct StatEvent {
+    pub op: Op,
+    pub outcome: Outcome,
+    pub latency: Duration,
+}
+
+/// Persistent id of the last reset time
+pub const RESET_AT_ID: u8 = u8::MAX;
+
+// Server statistics
+// All counters are atomic, so they can be updated concurrently.
+// Updates share the gate, while snapshots and resets take it exclusively,
+// so a snapshot never observes a partially applied update or reset.
+pub struct Stats {
+    started: Instant,
+    gate: RwLock<()>,
+    ops: [OpCounters; Op::ALL.len()],
+    reset_at: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            gate: RwLock::new(()),
+            ops: Default::default(),
+            reset_at: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, event: &StatEvent) {
+        let _shared = self.gate.read().unwrap_or_else(|e| e.into_inner());
+
+        let counters = &self.ops[event.op as usize];
+        counters.counter(event.outcome).fetch_add(1, Ordering::Relaxed);
+        counters.latency.record(event.latency);
+    }
+
+    /// Returns the operation counters keyed by their persistent id
+    /// Latency histograms are not included, they only cover the current run.
+    /// The last reset time is included under `RESET_AT_ID`, if the stats were ever reset.
+    pub fn counters(&self) -> Vec<(u8, u64)> {
+        let _exclusive = self.gate.write().unwrap_or_else(|e| e.into_inner());
+
+        let mut counters = Self::all_counters()
+            .map(|(op, outcome)| {
+                let count = self.ops[op as usize].counter(outcome).load(Ordering::Relaxed);
+                (Self::counter_id(op, outcome), count)
+            })
+            .collect::<Vec<_>>();
+
+        let reset_at = self.reset_at.load(Ordering::Relaxed);
+        if reset_at > 0 {
+            counters.push((RESET_AT_ID, reset_at));
+        }
+
+        counters
+    }
+
+    /// Adds persisted counters, as returned by `counters`, to the current ones
+    pub fn restore(&self, counters: &[(u8, u64)]) {
+        let _exclusive = self.gate.write().unwrap_or_else(|e| e.into_inner());
+
+        if let Some((_, reset_at)) = counters.iter().find(|(i, _)| *i == RESET_AT_ID) {
+            self.reset_at.store(*reset_at, Ordering::Relaxed);
+        }
+
+        for (op, outcome) in Self::all_counters() {
+            let id = Self::counter_id(op, outcome);
+            if let Some((_, count)) = counters.iter().find(|(i, _)| *i == id) {
+                self.ops[op as usize]
+                    .counter(outcome)
+                    .fetch_add(*count, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Zeroes all counters and latencies, and returns the reset time (UNIX seconds)
+    pub fn reset(&self) -> u64 {
+        let _exclusive = self.gate.write().unwrap_or_else(|e| e.into_inner());
+
+        for counters in &self.ops {
+            counters.reset();
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.reset_at.store(now, Ordering::Relaxed);
+
+        now
+    }
+
+    /// Moves all counters and latencies of another instance into this one
+    /// Returns the number of moved events.
+    pub fn merge(&self, other: &Stats) -> u64 {
+        let _taken = other.gate.write().unwrap_or_else(|e| e.into_inner());
+        let _shared = self.gate.read().unwrap_or_else(|e| e.into_inner());
+
+        let mut merged = 0;
+        for (op, outcome) in Self::all_counters() {
+            let count = other.ops[op as usize].counter(outcome).swap(0, Ordering::Relaxed);
+            self.ops[op as usize].counter(outcome).fetch_add(count, Ordering::Relaxed);
+            merged += count;
+        }
+
+        for op in Op::ALL {
+            self.ops[op as usize].latency.merge(&other.ops[op as usize].latency);
+        }
+
+        merged
+    }
+
+    /// Persistent id of a counter
+    /// Successful and failed gets keep ids 0 and 1 from the original stats DB layout.
+    pub fn counter_id(op: Op, outcome: Outcome) -> u8 {
+        op as u8 * Outcome::ALL.len() as u8 + outcome as u8
+    }
+
+    fn all_counters() -> impl Iterator<Item = (Op, Outcome)> {
+        Op::ALL
+            .into_iter()
+            .flat_map(|op| Outcome::ALL.map(|outcome| (op, outcome)))
+    }
+
+    /// Builds a report of the operation counters
+    /// Storage and cache figures are left for the caller to fill in.
+    pub fn report(&self) -> StatsReport {
+        let _exclusive = self.gate.write().unwrap_or_else(|e| e.into_inner());
+
+        let reset_at = self.reset_at.load(Ordering::Relaxed);
+        let ops = Op::ALL
+            .iter()
+            .map(|op| (op.name().to_owned(), self.ops[*op as usize].report()))
+            .collect::<BTreeMap<_, _>>();
+
+        let errors = ops.values().map(|o| o.err).sum();
+
+        StatsReport {
+            uptime_secs: self.started.elapsed().as_secs(),
+            reset_at: (reset_at > 0).then_some(reset_at),
+            ops,
+            counters: BTreeMap::from([("errors".to_owned(), errors)]),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Default)]
+struct OpCounters {
+    ok: AtomicU64,
+    not_found: AtomicU64,
+    err: AtomicU64,
+    latency: Histogram,
+}
+
+impl OpCounters {
+    fn counter(&self, outcome: Outcome) -> &AtomicU64 {
+        match outcome {
+            Outcome::Ok => &self.ok,
+            Outcome::NotFound => &self.not_found,
+            Outcome::Err => &self.err,
+        }
+    }
+
+    fn reset(&self) {
+        self.ok.store(0, Ordering::Relaxed);
+        self.not_found.store(0, Ordering::Relaxed);
+        self.err.store(0, Ordering::Relaxed);
+        self.latency.reset();
+    }
+
+    fn report(&self) -> OpStats {
+        let ok = self.ok.load(Ordering::Relaxed);
+        let not_found = self.not_found.load(Ordering::Relaxed);
+        let err = self.err.load(Ordering::Relaxed);
+
+        OpStats {
+            total: ok + not_found + err,
+            ok,
+            not_found,
+            err,
+            latency: self.latency.report(),
+        }
+    }
+}
+
+// Values below this are counted exactly, one bucket each
+const LINEAR: u64 = 16;
+// Each power of two above LINEAR is split into this many buckets (log2)
+const SUB_BITS: u32 = 3;
+// Latencies are capped at 2^MAX_EXP microseconds
+const MAX_EXP: u32 = 40;
+const BUCKETS: usize = LINEAR as usize + ((MAX_EXP - LINEAR.ilog2() + 1) << SUB_BITS) as usize;
+
+/// Lock-free latency histogram with log-linear buckets (~12% precision)
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let us = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[Self::bucket(us)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Moves the samples of another histogram into this one
+    pub fn merge(&self, other: &Histogram) {
+        for (bucket, other) in self.buckets.iter().zip(&other.buckets) {
+            bucket.fetch_add(other.swap(0, Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the upper bound of the bucket holding the given percentile
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let counts = self.buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+
+        let rank = ((percentile / 100.0) * total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::upper_bound(bucket);
+            }
+        }
+
+        Self::upper_bound(BUCKETS - 1)
+    }
+
+    pub fn report(&self) -> Latency {
+        Latency {
+            p50_us: self.percentile(50.0),
+            p90_us: self.percentile(90.0),
+            p99_us: self.percentile(99.0),
+        }
+    }
+
+    fn bucket(us: u64) -> usize {
+        if us < LINEAR {
+            return us as usize;
+        }
+
+        let exp = us.ilog2().min(MAX_EXP);
+        let us = us.min((1 << (MAX_EXP + 1)) - 1);
+        let sub = (us >> (exp - SUB_BITS)) & ((1 << SUB_BITS) - 1);
+
+        LINEAR as usize + (((exp - LINEAR.ilog2()) << SUB_BITS) as u64 + sub) as usize
+    }
+
+    fn upper_bound(bucket: usize) -> u64 {
+        if bucket < LINEAR as usize {
+            return bucket as u64;
+        }
+
+        let bucket = (bucket - LINEAR as usize) as u64;
+        let exp = (bucket >> SUB_BITS) as u32 + LINEAR.ilog2();
+        let sub = bucket & ((1 << SUB_BITS) - 1);
+        let width = 1 << (exp - SUB_BITS);
+
+        ((1 << SUB_BITS) + sub) * width + width - 1
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,59 @@
+use common::dto::{ErrorKind, Request};
+use server::acl::AccessControl;
+use server::config::Config;
+
+const CONFIG: &str = r#"
+[acl]
+default = { permissions = ["read"], prefixes = ["public/"] }
+
+[acl.clients.alice]
+permissions = ["read", "write"]
+prefixes = ["alice/", "public/"]
+
+[acl.clients.admin]
+permissions = ["read", "write", "admin"]
+"#;
+
+fn get(key: &str) -> Request {
+    Request::Get { key: key.to_owned() }
+}
+
+fn set(key: &str) -> Request {
+    Request::Set { key: key.to_owned(), val: "v".to_owned() }
+}
+
+#[test]
+fn test_acl_enforcement() {
+    let config: Config = toml::from_str(CONFIG).expect("bad config");
+    let acl = AccessControl::new(config.acl);
+
+    assert!(acl.check("alice", &set("alice/a")).is_ok(), "own prefix write");
+    assert!(acl.check("alice", &get("public/a")).is_ok(), "shared prefix read");
+    assert!(acl.check("admin", &set("any")).is_ok(), "admin without prefixes");
+    assert!(acl.check("admin", &Request::ResetStats).is_ok(), "admin stats");
+    assert!(acl.check("10.0.0.5", &get("public/a")).is_ok(), "default read");
+
+    for (client, req) in [("alice", set("bob/a")), ("alice", Request::Stats), ("10.0.0.5", set("public/a")), ("10.0.0.5", get("private"))] {
+        let e = acl.check(client, &req).expect_err("request allowed");
+        assert_eq!(e.kind, ErrorKind::Forbidden, "bad error kind");
+    }
+
+    // pings are always allowed
+    assert!(acl.check("10.0.0.5", &Request::Ping).is_ok(), "ping denied");
+
+    assert_eq!(acl.metrics()[0], ("acl.denied".to_owned(), 4), "bad denied count");
+}
+
+#[test]
+fn test_acl_without_default() {
+    let config: Config = toml::from_str("[acl.clients.alice]\npermissions = [\"read\"]").expect("bad config");
+    let acl = AccessControl::new(config.acl);
+
+    assert!(acl.check("alice", &get("a")).is_ok(), "listed client denied");
+    assert!(acl.check("bob", &get("a")).is_err(), "unlisted client allowed");
+
+    // without any ACL, everything is allowed
+    let empty: Config = toml::from_str("").expect("bad empty config");
+    let acl = AccessControl::new(empty.acl);
+    assert!(acl.check("bob", &set("a")).is_ok(), "denied without ACLs");
+}
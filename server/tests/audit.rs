@@ -0,0 +1,65 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use ::common::dto::{AuditEntry, AuditQuery, Error, ErrorKind, Request, Response};
+use ::common::net::Address;
+use server::audit::{self, AuditLog};
+
+fn set_entry(key: &str, timestamp_ms: u64) -> AuditEntry {
+    let address = Address::from(SocketAddr::from(([127, 0, 0, 1], 4000)));
+    let req = Request::Set { key: key.to_owned(), val: "v".to_owned() };
+
+    let mut entry = audit::entry(&req, "alice", &address).expect("set not audited");
+    entry.timestamp_ms = timestamp_ms;
+    entry.result = audit::result(&Response::Set);
+    entry
+}
+
+#[test]
+fn test_audit_entry() {
+    let address = Address::from(SocketAddr::from(([127, 0, 0, 1], 4000)));
+
+    let entry = set_entry("a", 0);
+    assert_eq!(entry.op, "set", "bad op");
+    assert_eq!(entry.key.as_deref(), Some("a"), "bad key");
+    assert_eq!(entry.value_hash.as_deref().map(str::len), Some(64), "bad value hash");
+    assert_eq!(entry.result, "ok", "bad result");
+
+    assert!(audit::entry(&Request::Get { key: "a".to_owned() }, "alice", &address).is_none(), "get audited");
+    assert!(audit::entry(&Request::ResetStats, "alice", &address).is_some(), "reset not audited");
+
+    let denied = Response::Error(Error::new(ErrorKind::Forbidden, "key a not allowed"));
+    assert!(audit::result(&denied).contains("forbidden"), "bad error result");
+}
+
+#[test]
+fn test_audit_rotation_and_query() {
+    let path = common::temp_dir("audit-rotation").join("audit.log");
+
+    // a few entries per file, and two rotated files kept
+    let log = AuditLog::open(&path, 600, 2).expect("failed open");
+    for i in 0..20 {
+        log.record(&set_entry(&format!("k{}", i % 2), i)).expect("failed record");
+    }
+
+    assert!(path.with_extension("log.2").exists(), "not rotated");
+    assert!(!path.with_extension("log.3").exists(), "too many files kept");
+
+    let all = log.query(&AuditQuery::default()).expect("failed query");
+    assert!(!all.is_empty() && all.len() < 20, "oldest files not dropped");
+    assert!(all.windows(2).all(|w| w[0].timestamp_ms < w[1].timestamp_ms), "not oldest first");
+    assert_eq!(all.last().map(|e| e.timestamp_ms), Some(19), "latest entry missing");
+
+    let query = AuditQuery { key: Some("k1".to_owned()), since_ms: Some(15), until_ms: Some(19) };
+    let found = log.query(&query).expect("failed query");
+    assert_eq!(found.iter().map(|e| e.timestamp_ms).collect::<Vec<_>>(), vec![15, 17], "bad filtered entries");
+
+    // reopening appends to the existing log
+    drop(log);
+    let log = AuditLog::open(&path, 600, 2).expect("failed reopen");
+    log.record(&set_entry("k0", 20)).expect("failed record");
+    assert_eq!(log.query(&AuditQuery::default()).expect("failed query").last().map(|e| e.timestamp_ms), Some(20), "not appended");
+
+    _ = std::fs::remove_dir_all(path.parent().unwrap());
+}
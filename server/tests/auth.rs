@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use server::auth::{Authenticator, UserConfig};
+use server::config::Config;
+
+#[test]
+fn test_verify_token() {
+    let users = HashMap::from([("alice".to_owned(), UserConfig::new("s3cret"))]);
+    let auth = Authenticator::new(users);
+
+    assert!(auth.is_required(), "auth not required");
+    assert!(auth.verify("alice", "s3cret"), "valid token refused");
+    assert!(!auth.verify("alice", "wrong"), "wrong token accepted");
+    assert!(!auth.verify("bob", "s3cret"), "unknown user accepted");
+
+    let metrics = auth.metrics();
+    assert_eq!(metrics[0], ("auth.succeeded".to_owned(), 1), "bad successes");
+    assert_eq!(metrics[1], ("auth.failed".to_owned(), 2), "bad failures");
+}
+
+#[test]
+fn test_salted_hash() {
+    let a = UserConfig::new("s3cret");
+    let b = UserConfig::new("s3cret");
+
+    assert_ne!(a.salt, b.salt, "salt reused");
+    assert_ne!(a.hash, b.hash, "same hash for different salts");
+}
+
+#[test]
+fn test_users_config() {
+    // sha256("0011" + "s3cret")
+    let config: Config = toml::from_str(&format!(r#"
+        [users.alice]
+        salt = "0011"
+        hash = "{}"
+    "#, sha256_hex("0011s3cret"))).expect("bad config");
+
+    let auth = Authenticator::new(config.users);
+    assert!(auth.verify("alice", "s3cret"), "configured token refused");
+
+    let empty: Config = toml::from_str("").expect("bad empty config");
+    assert!(!Authenticator::new(empty.users).is_required(), "auth required without users");
+}
+
+fn sha256_hex(s: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(Sha256::digest(s.as_bytes()))
+}
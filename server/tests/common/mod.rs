@@ -0,0 +1,12 @@
+// Helpers shared by the server tests
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+
+/// Empty directory for a test, named after it and the process so that concurrent runs don't clash
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("dict-{}-{}", name, std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("failed temp dir");
+    dir
+}
@@ -0,0 +1,289 @@
+mod common;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+
+use ::common::codec::CodecKind;
+use ::common::dto::{AuditQuery, ErrorKind, Hello, HelloReply, Request, RequestFrame, Response, ResponseFrame, Token};
+use ::common::net::{Address, JsonConnection, Listener, Transport};
+use server::Db;
+use server::acl::AccessControl;
+use server::audit::AuditLog;
+use server::auth::{Authenticator, UserConfig};
+use server::config::Config;
+use server::connection::{self, acquire_connection, Context, MAX_AUTH_FAILURES, REJECTION_TIMEOUT};
+use server::limits::{ConnectionLimiter, MAX_REJECTING};
+use server::pipeline::{stats_channel, Overflow};
+use server::ratelimit::RateLimiter;
+use server::stats::Stats;
+
+const ACL: &str = r#"
+[acl]
+default = { permissions = ["read"] }
+
+[acl.clients.alice]
+permissions = ["read", "write"]
+prefixes = ["alice/"]
+"#;
+
+// Server state with its storage and audit log in a temp dir named after the test
+fn context(name: &str, config: Config) -> Arc<Context> {
+    limited_context(name, config, ConnectionLimiter::new(0, 0))
+}
+
+// Server state with the given connection limits
+fn limited_context(name: &str, config: Config, limiter: Arc<ConnectionLimiter>) -> Arc<Context> {
+    let dir = common::temp_dir(name);
+
+    let dict = Db::<String, String>::create(dir.join("dict.db").to_str().unwrap(), "dict".to_owned())
+        .expect("failed create");
+    let audit = AuditLog::open(&dir.join("audit.log"), 1 << 20, 1)
+        .expect("failed audit log");
+
+    let (stats_producer, mut stats_consumer) = stats_channel(64, Overflow::Drop);
+    tokio::spawn(async move { while stats_consumer.recv().await.is_some() {} });
+
+    Arc::new(Context {
+        dict: Arc::new(dict),
+        stats: Arc::new(Stats::new()),
+        stats_producer,
+        limiter,
+        rate_limiter: RateLimiter::new(config.rate_limit),
+        auth: Authenticator::new(config.users),
+        acl: AccessControl::new(config.acl),
+        audit: Arc::new(audit),
+        idle_timeout: None,
+        request_timeout: None,
+        max_pipelined: 8,
+        codecs: CodecKind::ALL.to_vec(),
+        tls: None,
+    })
+}
+
+fn with_user(user: &str, token: &str) -> Config {
+    Config { users: HashMap::from([(user.to_owned(), UserConfig::new(token))]), ..Config::default() }
+}
+
+// Serves one end of an in-memory connection and returns the other, past the handshake
+async fn connect(identity: Option<&str>, ctx: &Arc<Context>) -> JsonConnection<DuplexStream> {
+    let (client, server) = tokio::io::duplex(4096);
+
+    let address = Address::from(SocketAddr::from(([127, 0, 0, 1], 4000)));
+    let admission = acquire_connection(&ctx.limiter, &address);
+    let served = JsonConnection::from_transport(server);
+    tokio::spawn(connection::serve(served, "json", address, identity.map(str::to_owned), admission, ctx.clone()));
+
+    let mut client = JsonConnection::from_transport(client);
+    client.respond(Hello::new(&["json"]))
+        .await
+        .expect("failed hello");
+
+    let reply = client.listen::<HelloReply>()
+        .await
+        .expect("no hello reply")
+        .expect("empty hello reply");
+    assert!(matches!(reply, HelloReply::Accepted { .. }), "bad hello reply {:?}", reply);
+
+    client
+}
+
+// Sends a request and waits for its response, checking the response id
+async fn request(client: &mut JsonConnection<DuplexStream>, id: u64, request: Request) -> Response {
+    client.respond(RequestFrame { id, timeout_ms: None, request })
+        .await
+        .expect("failed request");
+
+    let frame = client.listen::<ResponseFrame>()
+        .await
+        .expect("no response")
+        .expect("closed connection");
+    assert_eq!(frame.id, id, "bad response id");
+
+    frame.response
+}
+
+fn error_kind(response: &Response) -> Option<ErrorKind> {
+    match response {
+        Response::Error(e) => Some(e.kind),
+        _ => None,
+    }
+}
+
+fn get(key: &str) -> Request {
+    Request::Get { key: key.to_owned() }
+}
+
+fn set(key: &str) -> Request {
+    Request::Set { key: key.to_owned(), val: "v".to_owned() }
+}
+
+fn auth(user: &str, token: &str) -> Request {
+    Request::Auth { user: user.to_owned(), token: Token(token.to_owned()) }
+}
+
+#[tokio::test]
+async fn test_unauthorized_before_auth() {
+    let ctx = context("connection-unauthorized", with_user("alice", "s3cret"));
+    let mut client = connect(None, &ctx).await;
+
+    let response = request(&mut client, 1, get("a")).await;
+    assert_eq!(error_kind(&response), Some(ErrorKind::Unauthorized), "bad response {:?}", response);
+
+    // pings are answered without authentication
+    let response = request(&mut client, 2, Request::Ping).await;
+    assert!(matches!(response, Response::Pong), "bad response {:?}", response);
+
+    let response = request(&mut client, 3, auth("alice", "s3cret")).await;
+    assert!(matches!(response, Response::Auth), "bad response {:?}", response);
+
+    let response = request(&mut client, 4, get("a")).await;
+    assert_eq!(error_kind(&response), Some(ErrorKind::NotFound), "bad response {:?}", response);
+}
+
+#[tokio::test]
+async fn test_auth_failures_close() {
+    let ctx = context("connection-auth-failures", with_user("alice", "s3cret"));
+    let mut client = connect(None, &ctx).await;
+
+    for id in 1..=u64::from(MAX_AUTH_FAILURES) {
+        let response = request(&mut client, id, auth("alice", "wrong")).await;
+        assert_eq!(error_kind(&response), Some(ErrorKind::Unauthorized), "bad response {:?}", response);
+    }
+
+    let closed = tokio::time::timeout(Duration::from_secs(5), client.listen::<ResponseFrame>())
+        .await
+        .expect("connection not closed");
+    assert!(matches!(closed, Ok(None)), "bad close {:?}", closed);
+}
+
+#[tokio::test]
+async fn test_identity_skips_auth() {
+    let ctx = context("connection-identity", with_user("alice", "s3cret"));
+    let mut client = connect(Some("alice"), &ctx).await;
+
+    let response = request(&mut client, 1, set("a")).await;
+    assert!(matches!(response, Response::Set), "bad response {:?}", response);
+}
+
+#[tokio::test]
+async fn test_acl_denied() {
+    let config: Config = toml::from_str(ACL).expect("bad config");
+    let ctx = context("connection-acl", config);
+    let mut client = connect(Some("alice"), &ctx).await;
+
+    let response = request(&mut client, 1, set("bob/a")).await;
+    assert_eq!(error_kind(&response), Some(ErrorKind::Forbidden), "bad response {:?}", response);
+    assert_eq!(ctx.dict.get(&"bob/a".to_owned()).await.expect("failed get"), None, "denied write applied");
+
+    let response = request(&mut client, 2, set("alice/a")).await;
+    assert!(matches!(response, Response::Set), "bad response {:?}", response);
+
+    // the denied write is audited with its outcome
+    let entries = ctx.audit.query(&AuditQuery::default()).expect("failed query");
+    assert_eq!(entries.len(), 2, "bad audit entries {:?}", entries);
+    assert!(entries[0].result.contains("forbidden"), "bad denied result {:?}", entries[0]);
+    assert_eq!(entries[1].result, "ok", "bad allowed result");
+
+    // unknown clients are read-only
+    let mut client = connect(None, &ctx).await;
+    let response = request(&mut client, 1, set("alice/b")).await;
+    assert_eq!(error_kind(&response), Some(ErrorKind::Forbidden), "bad response {:?}", response);
+}
+
+#[tokio::test]
+async fn test_unknown_request() {
+    let ctx = context("connection-unknown", Config::default());
+    let mut client = connect(None, &ctx).await;
+
+    // a request from a newer client is refused by id, and the connection stays usable
+    let unknown = serde_json::json!({ "id": 7, "timeout_ms": null, "request": "Frobnicate" });
+    client.respond(unknown)
+        .await
+        .expect("failed request");
+
+    let frame = client.listen::<ResponseFrame>()
+        .await
+        .expect("no response")
+        .expect("closed connection");
+    assert_eq!(frame.id, 7, "bad response id");
+    assert_eq!(error_kind(&frame.response), Some(ErrorKind::InvalidRequest), "bad response {:?}", frame.response);
+
+    let response = request(&mut client, 8, Request::Ping).await;
+    assert!(matches!(response, Response::Pong), "bad response {:?}", response);
+}
+
+#[tokio::test]
+async fn test_request_deadline() {
+    let ctx = context("connection-deadline", Config::default());
+    let mut client = connect(None, &ctx).await;
+
+    // the client's budget ran out before the request was processed
+    client.respond(RequestFrame { id: 1, timeout_ms: Some(0), request: set("a") })
+        .await
+        .expect("failed request");
+
+    let frame = client.listen::<ResponseFrame>()
+        .await
+        .expect("no response")
+        .expect("closed connection");
+    assert_eq!(error_kind(&frame.response), Some(ErrorKind::Timeout), "bad response {:?}", frame.response);
+    assert_eq!(ctx.dict.get(&"a".to_owned()).await.expect("failed get"), None, "timed out write applied");
+
+    let entries = ctx.audit.query(&AuditQuery::default()).expect("failed query");
+    assert_eq!(entries.len(), 1, "bad audit entries {:?}", entries);
+    assert!(entries[0].result.contains("timed out"), "bad timed out result {:?}", entries[0]);
+
+    // without a budget the request is processed
+    let response = request(&mut client, 2, set("a")).await;
+    assert!(matches!(response, Response::Set), "bad response {:?}", response);
+}
+
+#[tokio::test]
+async fn test_rejections_bounded() {
+    let ctx = limited_context("connection-rejections", Config::default(), ConnectionLimiter::new(1, 0));
+    let _held = ctx.limiter.try_acquire(IpAddr::V4(Ipv4Addr::LOCALHOST)).expect("failed acquire");
+
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("failed bind");
+    let address = listener.local_addr().expect("no address");
+
+    // excess clients that never send their Hello
+    let mut clients = Vec::new();
+    for _ in 0..=MAX_REJECTING {
+        let client = TcpStream::connect(address)
+            .await
+            .expect("failed connect");
+        let (socket, peer) = listener.accept()
+            .await
+            .expect("failed accept");
+
+        let peer = Address::from(peer);
+        let admission = acquire_connection(&ctx.limiter, &peer);
+        tokio::spawn(connection::accept(Transport::Tcp(socket), peer, admission, ctx.clone()));
+        clients.push(client);
+    }
+    let started = Instant::now();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // past MAX_REJECTING, connections are closed without waiting for their handshake
+    let mut closed = 0;
+    for client in &mut clients {
+        if let Ok(read) = tokio::time::timeout(Duration::from_millis(10), client.read(&mut [0; 1])).await {
+            assert!(matches!(read, Ok(0)), "bad close {:?}", read);
+            closed += 1;
+        }
+    }
+    assert_eq!(closed, 1, "bad count of connections closed right away");
+
+    // the others are given a short time to be told why
+    for client in &mut clients {
+        let read = tokio::time::timeout(REJECTION_TIMEOUT * 2, client.read(&mut [0; 1])).await;
+        assert!(matches!(read, Ok(Ok(0))), "rejected connection not closed {:?}", read);
+    }
+    assert!(started.elapsed() < REJECTION_TIMEOUT * 2, "closed late, after {:?}", started.elapsed());
+}
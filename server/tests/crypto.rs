@@ -0,0 +1,125 @@
+mod common;
+
+use std::sync::Arc;
+
+use server::Db;
+use server::crypto::Cipher;
+
+#[test]
+fn test_cipher() {
+    let dir = common::temp_dir("crypto-cipher");
+    let keyfile = dir.join("keys");
+
+    assert_eq!(Cipher::generate(&keyfile).expect("failed generate"), 1, "bad first key id");
+    let cipher = Cipher::load(&keyfile, true).expect("failed load");
+
+    let key = cipher.seal_key(b"k");
+    assert_eq!(key, cipher.seal_key(b"k"), "key encryption not deterministic");
+    assert_ne!(&key[..], b"k", "key not encrypted");
+    assert_eq!(cipher.open_key(&key).expect("failed key decryption"), b"k", "bad key");
+
+    let val = cipher.seal_value(b"k", b"v");
+    assert_ne!(val, cipher.seal_value(b"k", b"v"), "value nonce reused");
+    assert_eq!(cipher.open_value(b"k", &val).expect("failed value decryption"), b"v", "bad value");
+    assert!(cipher.open_value(b"other", &val).is_err(), "value moved to another key");
+
+    // new keys become current, older ones still decrypt
+    assert_eq!(Cipher::generate(&keyfile).expect("failed generate"), 2, "bad second key id");
+    let rotated = Cipher::load(&keyfile, true).expect("failed reload");
+    assert_eq!(rotated.current_key(), 2, "bad current key");
+    assert!(rotated.is_stale(&key) && rotated.is_stale(&val), "old entry not stale");
+    assert_eq!(rotated.open_value(b"k", &val).expect("failed old value decryption"), b"v", "bad old value");
+    assert_eq!(rotated.sealed_keys(b"k").len(), 2, "bad key candidates");
+
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_encrypted_db_rotation() {
+    let dir = common::temp_dir("crypto-rotation");
+    let keyfile = dir.join("keys");
+    let path = dir.join("dict.db").to_str().unwrap().to_owned();
+
+    // data stored before encryption was enabled
+    let db = Db::<String, String>::create(&path, "dict".into()).expect("failed create");
+    db.set(&"plain".into(), &"old".into()).await.expect("failed set");
+    drop(db);
+
+    Cipher::generate(&keyfile).expect("failed generate");
+    let cipher = Arc::new(Cipher::load(&keyfile, true).expect("failed load"));
+    let db = Db::<String, String>::open_with(&path, "dict".into(), Some(cipher)).expect("failed open");
+
+    db.set(&"secret".into(), &"hunter2-value".into()).await.expect("failed set");
+    assert_eq!(db.reencrypt().await.expect("failed reencrypt"), 1, "plaintext entry not encrypted");
+    assert_eq!(db.len(), 2, "bad key count");
+    drop(db);
+
+    let raw = std::fs::read(&path).expect("failed read");
+    assert!(!raw.windows(13).any(|w| w == b"hunter2-value"), "plaintext value in storage");
+
+    assert!(Db::<String, String>::open(&path, "dict".into()).is_err(), "encrypted storage opened without keyfile");
+
+    // rotation: once re-encrypted, the old key isn't needed anymore
+    Cipher::generate(&keyfile).expect("failed generate");
+    let cipher = Arc::new(Cipher::load(&keyfile, true).expect("failed load"));
+    let db = Db::<String, String>::open_with(&path, "dict".into(), Some(cipher)).expect("failed open");
+    assert_eq!(db.reencrypt().await.expect("failed reencrypt"), 2, "entries not re-encrypted");
+    drop(db);
+
+    let keys = std::fs::read_to_string(&keyfile).expect("failed read keyfile");
+    std::fs::write(&keyfile, keys.lines().nth(1).expect("no second key")).expect("failed write keyfile");
+
+    let cipher = Arc::new(Cipher::load(&keyfile, true).expect("failed load"));
+    let db = Db::<String, String>::open_with(&path, "dict".into(), Some(cipher)).expect("failed open");
+    assert_eq!(db.get(&"plain".into()).await.expect("failed get").as_deref(), Some("old"), "bad migrated value");
+    assert_eq!(db.get(&"secret".into()).await.expect("failed get").as_deref(), Some("hunter2-value"), "bad value");
+    assert!(db.delete(&"secret".into()).await.expect("failed delete"), "encrypted key not deleted");
+    assert_eq!(db.len(), 1, "bad key count after delete");
+
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_key_mode_change() {
+    let dir = common::temp_dir("crypto-key-mode");
+    let keyfile = dir.join("keys");
+    let path = dir.join("dict.db").to_str().unwrap().to_owned();
+    Cipher::generate(&keyfile).expect("failed generate");
+
+    let open = |encrypt_keys| {
+        let cipher = Arc::new(Cipher::load(&keyfile, encrypt_keys).expect("failed load"));
+        Db::<String, String>::open_with(&path, "dict".into(), Some(cipher)).expect("failed open")
+    };
+
+    let db = Db::<String, String>::create(&path, "dict".into()).expect("failed create");
+    drop(db);
+
+    // plaintext keys, then encrypted ones
+    let db = open(false);
+    db.set(&"a".into(), &"1".into()).await.expect("failed set");
+    drop(db);
+
+    let db = open(true);
+    assert_eq!(db.get(&"a".into()).await.expect("failed get").as_deref(), Some("1"), "entry lost on key encryption");
+    assert_eq!(db.reencrypt().await.expect("failed reencrypt"), 1, "key not encrypted");
+    assert_eq!(db.reencrypt().await.expect("failed reencrypt"), 0, "key encrypted twice");
+    drop(db);
+
+    // and back to plaintext keys
+    let db = open(false);
+    assert_eq!(db.len(), 1, "bad key count");
+    assert_eq!(db.get(&"a".into()).await.expect("failed get").as_deref(), Some("1"), "entry lost on key decryption");
+    assert_eq!(db.reencrypt().await.expect("failed reencrypt"), 1, "key not decrypted");
+    drop(db);
+
+    let db = open(true);
+    db.set(&"b".into(), &"2".into()).await.expect("failed set");
+    drop(db);
+
+    let db = open(false);
+    assert!(db.delete(&"b".into()).await.expect("failed delete"), "entry with encrypted key not deleted");
+    assert_eq!(db.get(&"a".into()).await.expect("failed get").as_deref(), Some("1"), "bad value");
+    assert_eq!(db.len(), 1, "bad key count after delete");
+
+    _ = std::fs::remove_dir_all(&dir);
+}
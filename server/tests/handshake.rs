@@ -0,0 +1,34 @@
+use common::dto::{Hello, HelloReply, PROTOCOL_VERSION};
+use common::net::BincodeConnection;
+use server::handshake::negotiate;
+
+#[test]
+fn test_handshake_accepted() {
+    let mut hello = Hello::new(&["json", BincodeConnection::CODEC]);
+    hello.features.push("from_the_future".to_owned());
+
+    match negotiate(&hello, BincodeConnection::CODEC, 64) {
+        HelloReply::Accepted { version, codec, features, max_pipelined } => {
+            assert_eq!(version, PROTOCOL_VERSION, "bad version");
+            assert_eq!(codec, BincodeConnection::CODEC, "bad codec");
+            assert!(!features.contains(&"from_the_future".to_owned()), "unknown feature accepted");
+            assert!(features.contains(&"pipelining".to_owned()), "missing feature");
+            assert_eq!(max_pipelined, 64, "bad pipelining limit");
+        },
+        reply => panic!("handshake failed {:?}", reply),
+    }
+}
+
+#[test]
+fn test_handshake_rejected() {
+    let rejections = [
+        Hello { magic: 1, ..Hello::new(&[BincodeConnection::CODEC]) },
+        Hello { version: PROTOCOL_VERSION + 1, ..Hello::new(&[BincodeConnection::CODEC]) },
+        Hello::new(&["json"]),
+    ];
+
+    for hello in rejections {
+        let reply = negotiate(&hello, BincodeConnection::CODEC, 64);
+        assert!(matches!(reply, HelloReply::Rejected { .. }), "accepted {:?}", hello);
+    }
+}
@@ -0,0 +1,51 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use server::limits::{ConnectionLimiter, Rejection, MAX_REJECTING};
+
+const FIRST: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+const SECOND: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+#[test]
+fn test_connection_limits() {
+    let limiter = ConnectionLimiter::new(3, 2);
+
+    let a = limiter.try_acquire(FIRST).expect("first connection");
+    let _b = limiter.try_acquire(FIRST).expect("second connection");
+    assert_eq!(limiter.try_acquire(FIRST).err(), Some(Rejection::TooManyFromAddress), "per-ip limit");
+
+    let _c = limiter.try_acquire(SECOND).expect("other address");
+    assert_eq!(limiter.try_acquire(SECOND).err(), Some(Rejection::TooManyConnections), "total limit");
+
+    drop(a);
+    let _d = limiter.try_acquire(FIRST).expect("released connection");
+
+    let metrics = limiter.metrics();
+    assert!(metrics.contains(&("connections.active".to_owned(), 3)), "bad active count");
+    assert!(metrics.contains(&("connections.rejected".to_owned(), 2)), "bad rejected count");
+}
+
+#[test]
+fn test_rejections_bounded() {
+    let limiter = ConnectionLimiter::new(1, 0);
+
+    let rejecting: Vec<_> = (0..MAX_REJECTING)
+        .map(|_| limiter.try_reject().expect("rejection slot"))
+        .collect();
+    assert!(limiter.try_reject().is_none(), "too many rejections");
+
+    drop(rejecting);
+    assert!(limiter.try_reject().is_some(), "released rejection slots");
+}
+
+#[test]
+fn test_local_connections() {
+    let limiter = ConnectionLimiter::new(3, 1);
+
+    // local connections don't use the per-ip budget of any address
+    let _a = limiter.try_acquire_local().expect("first local connection");
+    let _b = limiter.try_acquire_local().expect("second local connection");
+    let _c = limiter.try_acquire(IpAddr::V4(Ipv4Addr::LOCALHOST)).expect("localhost connection");
+
+    // but they count towards the total
+    assert_eq!(limiter.try_acquire_local().err(), Some(Rejection::TooManyConnections), "total limit");
+}
@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use server::pipeline::{stats_channel, Overflow};
+use server::stats::{Op, Outcome, StatEvent, Stats};
+
+const EVENT: StatEvent = StatEvent { op: Op::Get, outcome: Outcome::Ok, latency: Duration::ZERO };
+
+fn metric(metrics: &[(String, u64)], name: &str) -> u64 {
+    metrics.iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| *v)
+        .expect("missing metric")
+}
+
+#[tokio::test]
+async fn test_overflow_drop() {
+    let (producer, mut consumer) = stats_channel(2, Overflow::Drop);
+    for _ in 0..5 {
+        producer.send(EVENT).await;
+    }
+
+    let metrics = producer.metrics();
+    assert_eq!(metric(&metrics, "pipeline.backlog"), 2, "bad backlog");
+    assert_eq!(metric(&metrics, "pipeline.dropped"), 3, "bad drop count");
+
+    consumer.recv().await.expect("no event");
+    assert_eq!(producer.backlog(), 1, "backlog not updated");
+}
+
+#[tokio::test]
+async fn test_overflow_aggregate() {
+    let (producer, mut consumer) = stats_channel(2, Overflow::Aggregate);
+    for _ in 0..5 {
+        producer.send(EVENT).await;
+    }
+
+    assert_eq!(metric(&producer.metrics(), "pipeline.aggregated"), 3, "bad aggregate count");
+
+    let stats = Stats::new();
+    while producer.backlog() > 0 {
+        stats.record(&consumer.recv().await.expect("no event"));
+    }
+    assert_eq!(consumer.drain_aggregate(&stats), 3, "bad drain");
+    assert_eq!(consumer.drain_aggregate(&stats), 0, "aggregate not emptied");
+    assert_eq!(stats.report().ops["get"].ok, 5, "events lost");
+}
+
+#[tokio::test]
+async fn test_overflow_block() {
+    let (producer, mut consumer) = stats_channel(1, Overflow::Block);
+    producer.send(EVENT).await;
+
+    let blocked = producer.clone();
+    let sender = tokio::spawn(async move { blocked.send(EVENT).await });
+    tokio::task::yield_now().await;
+
+    consumer.recv().await.expect("no event");
+    consumer.recv().await.expect("blocked event lost");
+    sender.await.expect("sender failed");
+
+    assert_eq!(metric(&producer.metrics(), "pipeline.blocked"), 1, "bad block count");
+}
@@ -0,0 +1,66 @@
+use server::config::{Config, Limit};
+use server::ratelimit::RateLimiter;
+use server::stats::Op;
+
+const CONFIG: &str = r#"
+[rate_limit]
+default = { rate = 100.0, burst = 200 }
+ops.set = { rate = 0.001, burst = 2 }
+
+[rate_limit.clients."10.0.0.5"]
+default = { rate = 1000.0, burst = 1000 }
+ops.get = { rate = 0.001, burst = 1 }
+"#;
+
+#[test]
+fn test_limit_resolution() {
+    let config: Config = toml::from_str(CONFIG).expect("bad config");
+    let limits = &config.rate_limit;
+
+    assert_eq!(limits.limit("10.0.0.5", Op::Get), Some(Limit { rate: 0.001, burst: 1 }), "client op limit");
+    assert_eq!(limits.limit("10.0.0.5", Op::Set), Some(Limit { rate: 1000.0, burst: 1000 }), "client default");
+    assert_eq!(limits.limit("10.0.0.6", Op::Set), Some(Limit { rate: 0.001, burst: 2 }), "op limit");
+    assert_eq!(limits.limit("10.0.0.6", Op::Get), Some(Limit { rate: 100.0, burst: 200 }), "default limit");
+}
+
+#[test]
+fn test_rate_limiter() {
+    let config: Config = toml::from_str(CONFIG).expect("bad config");
+    let limiter = RateLimiter::new(config.rate_limit);
+
+    assert!(limiter.check("10.0.0.6", Op::Set).is_ok(), "first in burst");
+    assert!(limiter.check("10.0.0.6", Op::Set).is_ok(), "second in burst");
+
+    let retry_after = limiter.check("10.0.0.6", Op::Set).expect_err("burst exceeded");
+    assert!(retry_after.as_secs() > 100, "bad retry delay {:?}", retry_after);
+
+    // buckets are separate per client and per operation
+    assert!(limiter.check("10.0.0.7", Op::Set).is_ok(), "other client");
+    assert!(limiter.check("10.0.0.6", Op::Get).is_ok(), "other operation");
+
+    assert_eq!(limiter.metrics()[0].1, 1, "bad limited count");
+}
+
+#[test]
+fn test_limit_validation() {
+    let config: Config = toml::from_str(CONFIG).expect("bad config");
+    config.validate().expect("valid config rejected");
+
+    for invalid in ["rate = nan, burst = 1", "rate = 0.0, burst = 1", "rate = -1.0, burst = 1", "rate = inf, burst = 1", "rate = 1.0, burst = 0"] {
+        let text = format!("[rate_limit.clients.alice]\ndefault = {{ {} }}", invalid);
+        let config: Config = toml::from_str(&text).expect("bad config");
+
+        let e = config.validate().expect_err("invalid limit accepted");
+        assert!(e.to_string().contains("alice"), "bad error {}", e);
+    }
+
+    // tiny rates wait as long as they can instead of overflowing
+    let config: Config = toml::from_str("[rate_limit]\ndefault = { rate = 1e-300, burst = 1 }").expect("bad config");
+    config.validate().expect("tiny rate rejected");
+
+    let limiter = RateLimiter::new(config.rate_limit);
+    assert!(limiter.check("alice", Op::Get).is_ok(), "first in burst");
+
+    let retry_after = limiter.check("alice", Op::Get).expect_err("burst exceeded");
+    assert_eq!(retry_after, std::time::Duration::MAX, "bad retry delay");
+}
@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use server::stats::{Histogram, Op, Outcome, StatEvent, Stats};
+
+#[test]
+fn test_histogram_percentiles() {
+    let histogram = Histogram::new();
+    assert_eq!(histogram.percentile(50.0), 0, "empty histogram");
+
+    for us in 1..=100 {
+        histogram.record(Duration::from_micros(us));
+    }
+
+    // buckets are exact below 16us and within ~12% above
+    let p50 = histogram.percentile(50.0);
+    let p99 = histogram.percentile(99.0);
+    assert!((50..=56).contains(&p50), "bad p50 {}", p50);
+    assert!((99..=111).contains(&p99), "bad p99 {}", p99);
+    assert_eq!(histogram.percentile(10.0), 10, "bad p10");
+}
+
+#[test]
+fn test_stats_report() {
+    let stats = Stats::new();
+
+    let events = [
+        (Op::Get, Outcome::Ok),
+        (Op::Get, Outcome::NotFound),
+        (Op::Get, Outcome::Err),
+        (Op::Set, Outcome::Ok),
+        (Op::Set, Outcome::Err),
+    ];
+    for (op, outcome) in events {
+        stats.record(&StatEvent { op, outcome, latency: Duration::from_micros(5) });
+    }
+
+    let report = stats.report();
+    let get = &report.ops["get"];
+    assert_eq!((get.total, get.ok, get.not_found, get.err), (3, 1, 1, 1), "bad get counters");
+    assert_eq!(get.latency.p99_us, 5, "bad get latency");
+
+    let set = &report.ops["set"];
+    assert_eq!((set.total, set.ok, set.err), (2, 1, 1), "bad set counters");
+
+    assert_eq!(report.ops["stats"].total, 0, "bad stats counters");
+    assert_eq!(report.counters["errors"], 2, "bad error count");
+}
+
+#[test]
+fn test_stats_restore() {
+    let stats = Stats::new();
+    stats.record(&StatEvent { op: Op::Get, outcome: Outcome::Ok, latency: Duration::ZERO });
+    stats.record(&StatEvent { op: Op::Get, outcome: Outcome::NotFound, latency: Duration::ZERO });
+    stats.record(&StatEvent { op: Op::Set, outcome: Outcome::Ok, latency: Duration::ZERO });
+
+    let counters = stats.counters();
+    assert!(counters.contains(&(0, 1)), "successful gets moved from id 0");
+    assert!(counters.contains(&(1, 1)), "failed gets moved from id 1");
+
+    let restored = Stats::new();
+    restored.restore(&counters);
+    assert_eq!(restored.counters(), counters, "bad restore");
+}
+
+#[test]
+fn test_stats_reset() {
+    let stats = Stats::new();
+    stats.record(&StatEvent { op: Op::Get, outcome: Outcome::Ok, latency: Duration::from_micros(10) });
+    assert_eq!(stats.report().reset_at, None, "never reset");
+
+    let reset_at = stats.reset();
+    let report = stats.report();
+    assert_eq!(report.reset_at, Some(reset_at), "bad reset time");
+    assert!(report.ops.values().all(|o| o.total == 0 && o.latency.p99_us == 0), "counters not reset");
+
+    let restored = Stats::new();
+    restored.restore(&stats.counters());
+    assert_eq!(restored.report().reset_at, Some(reset_at), "reset time not restored");
+}